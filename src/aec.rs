@@ -0,0 +1,313 @@
+use crate::config_file::AecConfig;
+use crate::fft::{Complex, Fft};
+use std::collections::VecDeque;
+
+// Acoustic echo canceller: a partitioned block frequency domain adaptive
+// filter (overlap-save, NLMS step per bin) per mic channel, driven by the
+// received channels as they are played. The reference is delayed by the bulk
+// delay GCC-PHAT finds between playback and capture, so the filter taps only
+// need to cover the room response.
+pub struct EchoCanceller {
+    block: usize,
+    fft: Fft,
+    n_partition: usize,
+    step: f32,
+    // newest sample last; long enough for the largest delay plus one window
+    ref_history: Vec<VecDeque<f32>>,
+    delay: usize,
+    delay_estimator: DelayEstimator,
+    // per reference, spectra of the last n_partition windows, newest first
+    ref_spectra: Vec<VecDeque<Vec<Complex>>>,
+    // smoothed reference power per bin, all references together
+    ref_power: Vec<f32>,
+    filters: Vec<EchoFilter>,
+    // partition whose gradient is constrained this block
+    constrain_next: usize,
+    scratch: Vec<Complex>,
+    echo: Vec<Complex>,
+}
+
+struct EchoFilter {
+    // [reference][partition][bin]
+    weights: Vec<Vec<Vec<Complex>>>,
+    // mic over reference power in single talk, tracked as a slowly rising minimum
+    echo_gain: Option<f32>,
+}
+
+// Nothing to adapt to below this reference power (mean square, full scale 1.0).
+const MIN_REF_POWER: f32 = 1e-8;
+// Near-end talk is assumed when the mic is this much louder than the echo alone.
+const DOUBLE_TALK_RATIO: f32 = 4.0;
+
+impl EchoCanceller {
+    pub fn new(
+        cfg: &AecConfig,
+        block: usize,
+        sample_rate: usize,
+        n_mic: usize,
+        n_ref: usize,
+    ) -> EchoCanceller {
+        let tail = cfg.tail_ms * sample_rate / 1000;
+        let n_partition = tail.div_ceil(block).max(1);
+        let max_delay = cfg.max_delay_ms * sample_rate / 1000;
+        let n_bin = 2 * block;
+        EchoCanceller {
+            block,
+            fft: Fft::new(n_bin),
+            n_partition,
+            step: cfg.step,
+            ref_history: vec![VecDeque::from(vec![0.0; max_delay + 2 * block]); n_ref],
+            delay: 0,
+            delay_estimator: DelayEstimator::new(max_delay),
+            ref_spectra: vec![VecDeque::from(vec![vec![Complex::ZERO; n_bin]; n_partition]); n_ref],
+            ref_power: vec![0.0; n_bin],
+            filters: (0..n_mic)
+                .map(|_| EchoFilter {
+                    weights: vec![vec![vec![Complex::ZERO; n_bin]; n_partition]; n_ref],
+                    echo_gain: None,
+                })
+                .collect(),
+            constrain_next: 0,
+            scratch: vec![Complex::ZERO; n_bin],
+            echo: vec![Complex::ZERO; n_bin],
+        }
+    }
+
+    // Remove the echo from one block of every mic channel in place.
+    pub fn process(&mut self, mics: &mut [Vec<f32>], references: &[Vec<f32>]) {
+        let block = self.block;
+        for (history, reference) in self.ref_history.iter_mut().zip(references.iter()) {
+            history.drain(..block);
+            history.extend(reference[..block].iter());
+        }
+
+        if let (Some(mic), Some(history)) = (mics.first(), self.ref_history.first()) {
+            let newest = history.range(history.len() - block..);
+            if let Some(delay) = self.delay_estimator.push(&mic[..block], newest) {
+                // keep a little room for the onset of the echo
+                let delay = delay.saturating_sub(block / 4);
+                if delay.abs_diff(self.delay) > block / 4 {
+                    println!("aec: echo delay {} samples", delay);
+                    self.delay = delay;
+                    self.reset();
+                }
+            }
+        }
+
+        // spectrum of the delayed reference window [previous block, this block]
+        let mut block_ref_power = 0.0;
+        for (history, spectra) in self.ref_history.iter().zip(self.ref_spectra.iter_mut()) {
+            let end = history.len() - self.delay;
+            let mut spectrum = spectra.pop_back().unwrap_or_default();
+            spectrum.resize(2 * block, Complex::ZERO);
+            for (x, &s) in spectrum.iter_mut().zip(history.range(end - 2 * block..end)) {
+                *x = Complex::new(s, 0.0);
+                block_ref_power += s * s;
+            }
+            self.fft.forward(&mut spectrum);
+            spectra.push_front(spectrum);
+        }
+        block_ref_power /= 2.0 * block as f32;
+        for (k, power) in self.ref_power.iter_mut().enumerate() {
+            let bin_power: f32 = self.ref_spectra.iter().map(|spectra| spectra[0][k].norm_sqr()).sum();
+            *power = 0.9 * *power + 0.1 * bin_power;
+        }
+
+        let constrain = self.constrain_next;
+        self.constrain_next = (self.constrain_next + 1) % self.n_partition;
+        for (mic, filter) in mics.iter_mut().zip(self.filters.iter_mut()) {
+            let mic = &mut mic[..block];
+            // echo estimate: last block of the filtered window
+            self.echo.fill(Complex::ZERO);
+            for (weights, spectra) in filter.weights.iter().zip(self.ref_spectra.iter()) {
+                for (w, x) in weights.iter().zip(spectra.iter()) {
+                    for ((y, &w), &x) in self.echo.iter_mut().zip(w.iter()).zip(x.iter()) {
+                        *y += w * x;
+                    }
+                }
+            }
+            self.fft.inverse(&mut self.echo);
+
+            let mic_power = mic.iter().map(|s| s * s).sum::<f32>() / block as f32;
+            let mut error_power = 0.0;
+            for (i, s) in mic.iter_mut().enumerate() {
+                *s -= self.echo[block + i].re;
+                error_power += *s * *s;
+            }
+            error_power /= block as f32;
+
+            if error_power > mic_power * DOUBLE_TALK_RATIO && mic_power > MIN_REF_POWER {
+                // the filter adds more than it removes; start over
+                filter.reset();
+                continue;
+            }
+            if block_ref_power < MIN_REF_POWER {
+                continue;
+            }
+            let ratio = mic_power / block_ref_power;
+            let echo_gain = filter.echo_gain.get_or_insert(ratio);
+            *echo_gain = if ratio < *echo_gain { ratio } else { *echo_gain * 1.002 };
+            if ratio > *echo_gain * DOUBLE_TALK_RATIO {
+                continue;
+            }
+
+            // error spectrum of [zeros, error]
+            for (i, e) in self.scratch.iter_mut().enumerate() {
+                *e = Complex::new(if i < block { 0.0 } else { mic[i - block] }, 0.0);
+            }
+            self.fft.forward(&mut self.scratch);
+            let mu = self.step / self.n_partition as f32;
+            for (weights, spectra) in filter.weights.iter_mut().zip(self.ref_spectra.iter()) {
+                for (p, (w, x)) in weights.iter_mut().zip(spectra.iter()).enumerate() {
+                    for k in 0..2 * block {
+                        let g = x[k].conj() * self.scratch[k];
+                        w[k] += g.scale(mu / (self.ref_power[k] + 1e-6));
+                    }
+                    if p == constrain {
+                        // keep the partition causal: taps beyond one block wrap around
+                        self.fft.inverse(w);
+                        w[block..].fill(Complex::ZERO);
+                        self.fft.forward(w);
+                    }
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        for filter in self.filters.iter_mut() {
+            filter.reset();
+        }
+        for spectra in self.ref_spectra.iter_mut() {
+            for spectrum in spectra.iter_mut() {
+                spectrum.fill(Complex::ZERO);
+            }
+        }
+    }
+}
+
+impl EchoFilter {
+    fn reset(&mut self) {
+        for weights in self.weights.iter_mut() {
+            for w in weights.iter_mut() {
+                w.fill(Complex::ZERO);
+            }
+        }
+        self.echo_gain = None;
+    }
+}
+
+// Bulk delay from reference to mic with GCC-PHAT over frames long enough for
+// 'max_delay'; the smoothed cross spectrum makes single frames of near-end
+// speech or silence harmless.
+struct DelayEstimator {
+    max_delay: usize,
+    frame: usize,
+    fft: Fft,
+    mic: Vec<f32>,
+    reference: Vec<f32>,
+    cross: Vec<Complex>,
+    mic_spectrum: Vec<Complex>,
+    ref_spectrum: Vec<Complex>,
+}
+
+impl DelayEstimator {
+    fn new(max_delay: usize) -> DelayEstimator {
+        let frame = (2 * max_delay).max(256).next_power_of_two();
+        DelayEstimator {
+            max_delay,
+            frame,
+            fft: Fft::new(2 * frame),
+            mic: Vec::with_capacity(frame),
+            reference: Vec::with_capacity(frame),
+            cross: vec![Complex::ZERO; 2 * frame],
+            mic_spectrum: vec![Complex::ZERO; 2 * frame],
+            ref_spectrum: vec![Complex::ZERO; 2 * frame],
+        }
+    }
+
+    // Returns a delay once per frame when the correlation peak is clear.
+    fn push<'a>(&mut self, mic: &[f32], reference: impl Iterator<Item = &'a f32>) -> Option<usize> {
+        self.mic.extend_from_slice(mic);
+        self.reference.extend(reference);
+        if self.mic.len() < self.frame {
+            return None;
+        }
+        let ref_power = self.reference.iter().map(|s| s * s).sum::<f32>() / self.frame as f32;
+        if ref_power < MIN_REF_POWER {
+            self.mic.clear();
+            self.reference.clear();
+            return None;
+        }
+        self.fft.forward_real(&self.mic, &mut self.mic_spectrum);
+        self.fft.forward_real(&self.reference, &mut self.ref_spectrum);
+        self.mic.clear();
+        self.reference.clear();
+        for ((c, &m), &x) in self
+            .cross
+            .iter_mut()
+            .zip(self.mic_spectrum.iter())
+            .zip(self.ref_spectrum.iter())
+        {
+            let product = m * x.conj();
+            let phat = product.scale(1.0 / (product.norm_sqr().sqrt() + 1e-12));
+            *c = c.scale(0.7) + phat.scale(0.3);
+        }
+        let mut correlation = self.cross.clone();
+        self.fft.inverse(&mut correlation);
+        let lags = &correlation[..=self.max_delay.min(self.frame - 1)];
+        let (delay, peak) = lags
+            .iter()
+            .enumerate()
+            .map(|(lag, c)| (lag, c.re))
+            .fold((0, f32::MIN), |best, lag| if lag.1 > best.1 { lag } else { best });
+        let mean = lags.iter().map(|c| c.re.abs()).sum::<f32>() / lags.len() as f32;
+        (peak > 8.0 * mean).then_some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // white noise in -1.0..1.0 from a xorshift generator
+    fn noise(n: usize) -> Vec<f32> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        (0..n)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 40) as f32 / (1 << 23) as f32 - 1.0
+            })
+            .collect()
+    }
+
+    #[test]
+    fn delay_estimator_finds_known_delay() {
+        let delay = 123;
+        let block = 128;
+        let reference = noise(8 * 1024);
+        let mic: Vec<f32> = (0..reference.len())
+            .map(|i| if i < delay { 0.0 } else { 0.5 * reference[i - delay] })
+            .collect();
+
+        let mut estimator = DelayEstimator::new(400);
+        let mut estimates = Vec::new();
+        for (mic, reference) in mic.chunks(block).zip(reference.chunks(block)) {
+            estimates.extend(estimator.push(mic, reference.iter()));
+        }
+        assert!(!estimates.is_empty());
+        assert!(estimates.iter().all(|&estimate| estimate == delay), "{estimates:?}");
+    }
+
+    #[test]
+    fn delay_estimator_ignores_silent_reference() {
+        let mut estimator = DelayEstimator::new(400);
+        let mic = noise(4 * 1024);
+        let silence = vec![0.0; mic.len()];
+        for (mic, reference) in mic.chunks(128).zip(silence.chunks(128)) {
+            assert_eq!(estimator.push(mic, reference.iter()), None);
+        }
+    }
+}
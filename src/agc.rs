@@ -0,0 +1,217 @@
+use crate::config_file::AgcConfig;
+use std::collections::VecDeque;
+
+// time constant of the level detector
+const DETECTOR_MS: f32 = 20.0;
+
+// Automatic gain control followed by a look-ahead peak limiter. Channels are
+// put in groups that share one gain: all mics when linked, otherwise each
+// channel alone; beams are always alone.
+//
+// The limiter takes the minimum of the gain every sample needs to stay under
+// the ceiling over a window of 'lookahead' samples, and smooths it with a
+// moving average of the same length; with the signal delayed by lookahead - 1
+// samples, the averaged gain is down to what a peak needs when it comes out.
+pub struct GainControl {
+    agc: bool,
+    target_db: f32,
+    max_gain_db: f32,
+    min_gain_db: f32,
+    attack: f32,
+    release: f32,
+    gate_db: f32,
+    detector: f32,
+    // linear peak ceiling, None without the limiter
+    ceiling: Option<f32>,
+    limiter_release: f32,
+    lookahead: usize,
+    // mean square level per channel
+    levels: Vec<f32>,
+    groups: Vec<Vec<usize>>,
+    states: Vec<GroupState>,
+    delay: Vec<VecDeque<f32>>,
+    sample: u64,
+}
+
+struct GroupState {
+    gain_db: f32,
+    // (sample, needed gain), increasing gains from the front
+    minimum: VecDeque<(u64, f32)>,
+    released: f32,
+    average: VecDeque<f32>,
+    sum: f64,
+}
+
+fn coefficient(ms: f32, sample_rate: usize) -> f32 {
+    1.0 - (-1000.0 / (ms.max(0.1) * sample_rate as f32)).exp()
+}
+
+impl GainControl {
+    // None when neither the AGC nor the limiter is enabled.
+    pub fn new(cfg: &AgcConfig, sample_rate: usize, n_mic: usize, n_beam: usize) -> Option<GainControl> {
+        if !cfg.enabled && !cfg.limiter {
+            return None;
+        }
+        let mut groups: Vec<Vec<usize>> = if cfg.linked {
+            vec![(0..n_mic).collect()]
+        } else {
+            (0..n_mic).map(|channel| vec![channel]).collect()
+        };
+        groups.extend((n_mic..n_mic + n_beam).map(|channel| vec![channel]));
+        groups.retain(|group| !group.is_empty());
+        let lookahead = if cfg.limiter {
+            (cfg.lookahead_ms * sample_rate / 1000).max(1)
+        } else {
+            1
+        };
+        let n_channel = n_mic + n_beam;
+        Some(GainControl {
+            agc: cfg.enabled,
+            target_db: cfg.target_db,
+            max_gain_db: cfg.max_gain_db,
+            min_gain_db: cfg.min_gain_db,
+            attack: coefficient(cfg.attack_ms as f32, sample_rate),
+            release: coefficient(cfg.release_ms as f32, sample_rate),
+            gate_db: cfg.gate_db,
+            detector: coefficient(DETECTOR_MS, sample_rate),
+            ceiling: cfg.limiter.then(|| 10_f32.powf(cfg.limit_db / 20.0)),
+            limiter_release: coefficient(cfg.limiter_release_ms as f32, sample_rate),
+            lookahead,
+            levels: vec![0.0; n_channel],
+            states: groups
+                .iter()
+                .map(|_| GroupState {
+                    gain_db: 0.0,
+                    minimum: VecDeque::with_capacity(lookahead + 1),
+                    released: 1.0,
+                    average: VecDeque::with_capacity(lookahead + 1),
+                    sum: 0.0,
+                })
+                .collect(),
+            groups,
+            delay: vec![VecDeque::with_capacity(lookahead + 1); n_channel],
+            sample: 0,
+        })
+    }
+
+    // One chunk of every channel in; what comes out of the look-ahead delay
+    // is appended to 'output'. The first lookahead - 1 samples only fill the
+    // delay, nothing is made up in their place.
+    #[allow(clippy::needless_range_loop)]
+    pub fn process(&mut self, input: &[&[f32]], output: &mut [VecDeque<f32>]) {
+        let n = input.first().map_or(0, |channel| channel.len());
+        for t in 0..n {
+            for (group, state) in self.groups.iter().zip(self.states.iter_mut()) {
+                let mut level = 0.0_f32;
+                for &channel in group.iter() {
+                    let x = input[channel][t];
+                    self.levels[channel] += self.detector * (x * x - self.levels[channel]);
+                    level = level.max(self.levels[channel]);
+                }
+                if self.agc {
+                    let level_db = 10.0 * (level + 1e-20).log10();
+                    if level_db >= self.gate_db {
+                        let wanted = (self.target_db - level_db).clamp(self.min_gain_db, self.max_gain_db);
+                        let rate = if wanted < state.gain_db { self.attack } else { self.release };
+                        state.gain_db += rate * (wanted - state.gain_db);
+                    }
+                }
+                let gain = 10_f32.powf(state.gain_db / 20.0);
+
+                let mut needed = 1.0;
+                if let Some(ceiling) = self.ceiling {
+                    let peak = group.iter().map(|&channel| input[channel][t].abs()).fold(0.0, f32::max) * gain;
+                    if peak > ceiling {
+                        needed = ceiling / peak;
+                    }
+                }
+                while state.minimum.back().is_some_and(|&(_, back)| back >= needed) {
+                    state.minimum.pop_back();
+                }
+                state.minimum.push_back((self.sample, needed));
+                while state.minimum.front().is_some_and(|&(sample, _)| sample + self.lookahead as u64 <= self.sample) {
+                    state.minimum.pop_front();
+                }
+                let minimum = state.minimum.front().map_or(1.0, |&(_, gain)| gain);
+                state.released = minimum.min(state.released + self.limiter_release * (1.0 - state.released));
+                state.average.push_back(state.released);
+                state.sum += state.released as f64;
+                if state.average.len() > self.lookahead {
+                    state.sum -= state.average.pop_front().unwrap_or(0.0) as f64;
+                }
+                let limit = (state.sum / state.average.len() as f64) as f32;
+
+                for &channel in group.iter() {
+                    let delay = &mut self.delay[channel];
+                    delay.push_back(input[channel][t] * gain);
+                    if delay.len() == self.lookahead {
+                        let x = delay.pop_front().unwrap_or(0.0);
+                        output[channel].push_back(x * limit);
+                    }
+                }
+            }
+            self.sample += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: usize = 16000;
+
+    #[test]
+    fn limiter_never_exceeds_ceiling() {
+        let cfg = AgcConfig {
+            enabled: true,
+            linked: false,
+            max_gain_db: 30.0,
+            limiter: true,
+            limit_db: -6.0,
+            ..AgcConfig::default()
+        };
+        let ceiling = 10_f32.powf(cfg.limit_db / 20.0);
+        let lookahead = cfg.lookahead_ms * SAMPLE_RATE / 1000;
+        let mut gain_control = GainControl::new(&cfg, SAMPLE_RATE, 2, 1).unwrap();
+
+        // quiet noise the AGC turns up, with full scale clicks now and then
+        let mut seed = 1_u32;
+        let channels: Vec<Vec<f32>> = (0..3)
+            .map(|channel| {
+                (0..SAMPLE_RATE * 2)
+                    .map(|t| {
+                        seed ^= seed << 13;
+                        seed ^= seed >> 17;
+                        seed ^= seed << 5;
+                        let noise = (seed as f32 / u32::MAX as f32 * 2.0 - 1.0) * 0.01;
+                        if t % (997 + channel * 101) < 3 {
+                            1.0
+                        } else {
+                            noise
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        let mut output = vec![VecDeque::new(); 3];
+        let mut start = 0;
+        for chunk in [1, 37, 128, 160, 1000].iter().cycle() {
+            let end = (start + chunk).min(SAMPLE_RATE * 2);
+            let input: Vec<&[f32]> = channels.iter().map(|channel| &channel[start..end]).collect();
+            gain_control.process(&input, &mut output);
+            start = end;
+            if start == SAMPLE_RATE * 2 {
+                break;
+            }
+        }
+
+        for (channel, output) in output.iter().enumerate() {
+            assert_eq!(output.len(), SAMPLE_RATE * 2 - (lookahead - 1));
+            let peak = output.iter().fold(0.0_f32, |peak, x| peak.max(x.abs()));
+            assert!(peak <= ceiling + 1e-5, "channel {channel}: peak {peak} above {ceiling}");
+            // the clicks still come through, as loud as the ceiling allows
+            assert!(peak > ceiling * 0.9, "channel {channel}: peak {peak}");
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::sync::atomic::{fence, AtomicI64, AtomicU64, Ordering};
+use crossbeam::queue::ArrayQueue;
+
+// Capture time of one sample on the JACK clock (jack_get_time).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ClockReference {
+    // index of the sample in the capture stream
+    pub sample: u64,
+    // JACK time the sample was captured, in microseconds
+    pub jack_micros: i64,
+}
+
+impl ClockReference {
+    pub fn jack_micros_at(&self, sample: u64, sample_rate: usize) -> i64 {
+        let delta = sample as i64 - self.sample as i64;
+        self.jack_micros + delta * 1_000_000 / sample_rate as i64
+    }
+}
+
+// Rate of the audio device, which clock references and capture gaps count
+// in, and rate of the streams, which packets count in.
+#[derive(Clone, Copy, Debug)]
+pub struct SampleRates {
+    pub device: usize,
+    pub stream: usize,
+}
+
+impl SampleRates {
+    pub fn device_sample(&self, stream_sample: u64) -> u64 {
+        stream_sample * self.device as u64 / self.stream as u64
+    }
+
+    // Stream sample 'device_offset' device samples after 'stream_sample',
+    // rounded down once rather than the offset on its own.
+    pub fn stream_sample_after(&self, stream_sample: u64, device_offset: u64) -> u64 {
+        (stream_sample * self.device as u64 + device_offset * self.stream as u64) / self.device as u64
+    }
+}
+
+// Latest clock reference published by the audio callback every cycle. A
+// seqlock keeps the writer wait-free so it is safe to update from the RT thread.
+#[derive(Default)]
+pub struct AudioClock {
+    seq: AtomicU64,
+    sample: AtomicU64,
+    jack_micros: AtomicI64,
+}
+
+impl AudioClock {
+    pub fn new() -> AudioClock {
+        AudioClock::default()
+    }
+
+    // Only one thread (the audio callback) may publish.
+    pub fn publish(&self, reference: ClockReference) {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.sample.store(reference.sample, Ordering::Relaxed);
+        self.jack_micros.store(reference.jack_micros, Ordering::Relaxed);
+        self.seq.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn load(&self) -> ClockReference {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq & 1 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let reference = ClockReference {
+                sample: self.sample.load(Ordering::Relaxed),
+                jack_micros: self.jack_micros.load(Ordering::Relaxed),
+            };
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                return reference;
+            }
+        }
+    }
+}
+
+// Discontinuities in the capture stream: cycles dropped by the audio callback
+// because the capture ring buffers were full, and time the device was down.
+// Ring buffer positions count only the samples written, so each gap is
+// recorded with the total missing so far; the sender adds that to ring
+// positions past the gap to get the capture sample index back.
+pub struct CaptureGaps {
+    // (ring sample index of the gap, total samples missing up to and including it)
+    gaps: ArrayQueue<(u64, u64)>,
+    missing_samples: AtomicU64,
+    pub overruns: AtomicU64,
+    pub dropped_samples: AtomicU64,
+}
+
+impl CaptureGaps {
+    pub fn new() -> CaptureGaps {
+        CaptureGaps {
+            gaps: ArrayQueue::new(64),
+            missing_samples: AtomicU64::new(0),
+            overruns: AtomicU64::new(0),
+            dropped_samples: AtomicU64::new(0),
+        }
+    }
+
+    // Called from the audio callback only.
+    pub fn record_overrun(&self, ring_sample: u64, dropped: u64) {
+        self.overruns.fetch_add(1, Ordering::Relaxed);
+        self.dropped_samples.fetch_add(dropped, Ordering::Relaxed);
+        self.record_skip(ring_sample, dropped);
+    }
+
+    // Called from the audio callback only.
+    pub fn record_skip(&self, ring_sample: u64, skipped: u64) {
+        let total = self.missing_samples.fetch_add(skipped, Ordering::Relaxed) + skipped;
+        // totals are cumulative, so losing the oldest entry only delays the correction
+        self.gaps.force_push((ring_sample, total));
+    }
+
+    pub fn pop(&self) -> Option<(u64, u64)> {
+        self.gaps.pop()
+    }
+}
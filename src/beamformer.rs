@@ -0,0 +1,381 @@
+use crate::config_file::{BeamConfig, BeamMethod, BeamformerConfig};
+use crate::fft::{Complex, Fft};
+use arc_swap::ArcSwap;
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+// Beams over the mic array, formed per frequency bin on frames of two blocks
+// (square root Hann window, 50% overlap-add). Delay-and-sum averages the mics
+// aligned on the look direction; MVDR minimizes the output power while
+// passing the look direction unchanged, from the spatial covariance of the
+// mics. The beams of a frame complete the block before the newest one.
+pub struct Beamformer {
+    block: usize,
+    fft: Fft,
+    window: Vec<f32>,
+    sample_rate: usize,
+    speed_of_sound: f32,
+    // relative to the centre of the array
+    positions: Vec<[f32; 3]>,
+    directions: Arc<ArcSwap<Vec<BeamConfig>>>,
+    steered: Arc<Vec<BeamConfig>>,
+    // [beam][bin][mic] for bins 0..=block
+    steering: Vec<Vec<Vec<Complex>>>,
+    weights: Vec<Vec<Vec<Complex>>>,
+    previous: Vec<Vec<f32>>,
+    // [mic][bin] of the current frame
+    spectra: Vec<Vec<Complex>>,
+    // [bin][row * n_mic + column], upper triangle; empty with a single mic
+    covariance: Vec<Vec<Complex>>,
+    forget: f32,
+    loading: f32,
+    update_blocks: usize,
+    blocks_to_update: usize,
+    // [beam] second half of the last synthesized frame
+    overlap: Vec<Vec<f32>>,
+    scratch: Vec<Complex>,
+    matrix: Vec<Complex>,
+    solution: Vec<Complex>,
+}
+
+impl Beamformer {
+    pub fn new(
+        cfg: &BeamformerConfig,
+        block: usize,
+        sample_rate: usize,
+        n_mic: usize,
+        directions: Arc<ArcSwap<Vec<BeamConfig>>>,
+    ) -> Beamformer {
+        let mut positions = cfg.mic_positions.clone();
+        if positions.len() > n_mic {
+            println!(
+                "beamformer: {} mic positions for {} mic channels, the rest are ignored",
+                positions.len(),
+                n_mic
+            );
+            positions.truncate(n_mic);
+        }
+        let n = positions.len() as f32;
+        let centre: Vec<f32> = (0..3).map(|i| positions.iter().map(|p| p[i]).sum::<f32>() / n).collect();
+        for p in positions.iter_mut() {
+            for i in 0..3 {
+                p[i] -= centre[i];
+            }
+        }
+
+        let n_mic = positions.len();
+        let n_bin = block + 1;
+        let steered = directions.load_full();
+        let n_beam = steered.len();
+        let frame_len = 2 * block;
+        let mut beamformer = Beamformer {
+            block,
+            fft: Fft::new(frame_len),
+            window: (0..frame_len)
+                .map(|i| (0.5 - 0.5 * (2.0 * PI * i as f32 / frame_len as f32).cos()).sqrt())
+                .collect(),
+            sample_rate,
+            speed_of_sound: cfg.speed_of_sound,
+            positions,
+            directions,
+            steered,
+            steering: vec![vec![vec![Complex::ZERO; n_mic]; n_bin]; n_beam],
+            weights: vec![vec![vec![Complex::ZERO; n_mic]; n_bin]; n_beam],
+            previous: vec![vec![0.0; block]; n_mic],
+            spectra: vec![vec![Complex::ZERO; n_bin]; n_mic],
+            // kept whenever MVDR can work, so a beam switched to it at runtime
+            // finds the covariance already averaged
+            covariance: if n_mic >= 2 {
+                vec![vec![Complex::ZERO; n_mic * n_mic]; n_bin]
+            } else {
+                Vec::new()
+            },
+            forget: (-(block as f32) / (cfg.mvdr_average_ms.max(1) * sample_rate) as f32 * 1000.0).exp(),
+            loading: cfg.mvdr_loading,
+            update_blocks: (cfg.mvdr_update_ms * sample_rate / 1000 / block).max(1),
+            blocks_to_update: 0,
+            overlap: vec![vec![0.0; block]; n_beam],
+            scratch: vec![Complex::ZERO; frame_len],
+            matrix: vec![Complex::ZERO; n_mic * n_mic],
+            solution: vec![Complex::ZERO; n_mic],
+        };
+        beamformer.steer();
+        beamformer
+    }
+
+    pub fn n_beam(&self) -> usize {
+        self.overlap.len()
+    }
+
+    // One block of every mic in, the beams of the block before it out.
+    pub fn process(&mut self, mics: &[Vec<f32>], beams: &mut [Vec<f32>]) {
+        let block = self.block;
+        let directions = self.directions.load_full();
+        if !Arc::ptr_eq(&directions, &self.steered) {
+            self.steered = directions;
+            self.steer();
+        }
+
+        for ((previous, mic), spectrum) in
+            self.previous.iter_mut().zip(mics.iter()).zip(self.spectra.iter_mut())
+        {
+            for (i, x) in self.scratch.iter_mut().enumerate() {
+                let sample = if i < block { previous[i] } else { mic[i - block] };
+                *x = Complex::new(sample * self.window[i], 0.0);
+            }
+            previous.copy_from_slice(&mic[..block]);
+            self.fft.forward(&mut self.scratch);
+            spectrum.copy_from_slice(&self.scratch[..=block]);
+        }
+
+        if !self.covariance.is_empty() {
+            let n_mic = self.positions.len();
+            let forget = self.forget;
+            for (k, covariance) in self.covariance.iter_mut().enumerate() {
+                for i in 0..n_mic {
+                    let x = self.spectra[i][k];
+                    for j in i..n_mic {
+                        let r = &mut covariance[i * n_mic + j];
+                        *r = r.scale(forget) + (x * self.spectra[j][k].conj()).scale(1.0 - forget);
+                    }
+                }
+            }
+            if self.blocks_to_update == 0 {
+                self.update_mvdr();
+                self.blocks_to_update = self.update_blocks;
+            }
+            self.blocks_to_update -= 1;
+        }
+
+        for ((weights, overlap), out) in self.weights.iter().zip(self.overlap.iter_mut()).zip(beams.iter_mut()) {
+            for k in 0..=block {
+                let mut y = Complex::ZERO;
+                for (w, spectrum) in weights[k].iter().zip(self.spectra.iter()) {
+                    y += w.conj() * spectrum[k];
+                }
+                if k == 0 || k == block {
+                    y.im = 0.0;
+                } else {
+                    self.scratch[2 * block - k] = y.conj();
+                }
+                self.scratch[k] = y;
+            }
+            self.fft.inverse(&mut self.scratch);
+            for i in 0..block {
+                out[i] = overlap[i] + self.scratch[i].re * self.window[i];
+                overlap[i] = self.scratch[block + i].re * self.window[block + i];
+            }
+        }
+    }
+
+    // Steering vectors of the current look directions, with delay-and-sum
+    // weights until MVDR weights are computed.
+    fn steer(&mut self) {
+        let n_mic = self.positions.len();
+        let frame_len = 2 * self.block;
+        for ((beam, steering), weights) in
+            self.steered.iter().zip(self.steering.iter_mut()).zip(self.weights.iter_mut())
+        {
+            let (azimuth, elevation) = (beam.azimuth.to_radians(), beam.elevation.to_radians());
+            let look = [
+                elevation.cos() * azimuth.cos(),
+                elevation.cos() * azimuth.sin(),
+                elevation.sin(),
+            ];
+            for (k, (d, w)) in steering.iter_mut().zip(weights.iter_mut()).enumerate() {
+                let freq = (k * self.sample_rate) as f32 / frame_len as f32;
+                for (m, p) in self.positions.iter().enumerate() {
+                    // a mic closer to the source hears it earlier
+                    let lead = (p[0] * look[0] + p[1] * look[1] + p[2] * look[2]) / self.speed_of_sound;
+                    d[m] = Complex::from_phase(2.0 * PI * freq * lead);
+                    w[m] = d[m].scale(1.0 / n_mic as f32);
+                }
+            }
+        }
+        if !self.covariance.is_empty() {
+            self.update_mvdr();
+        }
+    }
+
+    // w = R⁻¹d / (dᴴR⁻¹d) per bin, with R diagonally loaded.
+    fn update_mvdr(&mut self) {
+        let n_mic = self.positions.len();
+        for ((beam, steering), weights) in
+            self.steered.iter().zip(self.steering.iter()).zip(self.weights.iter_mut())
+        {
+            if beam.method != BeamMethod::Mvdr {
+                continue;
+            }
+            for ((d, w), covariance) in steering.iter().zip(weights.iter_mut()).zip(self.covariance.iter()) {
+                let trace: f32 = (0..n_mic).map(|i| covariance[i * n_mic + i].re).sum();
+                let load = self.loading * trace / n_mic as f32;
+                for i in 0..n_mic {
+                    for j in 0..n_mic {
+                        self.matrix[i * n_mic + j] = if i <= j {
+                            covariance[i * n_mic + j]
+                        } else {
+                            covariance[j * n_mic + i].conj()
+                        };
+                    }
+                    self.matrix[i * n_mic + i].re += load;
+                }
+                self.solution.copy_from_slice(d);
+                let gain = if trace > 1e-12 && solve(&mut self.matrix, &mut self.solution, n_mic) {
+                    let mut response = Complex::ZERO;
+                    for (d, z) in d.iter().zip(self.solution.iter()) {
+                        response += d.conj() * *z;
+                    }
+                    (response.norm_sqr() > 1e-20).then(|| response.recip())
+                } else {
+                    None
+                };
+                match gain {
+                    Some(gain) => {
+                        for (w, z) in w.iter_mut().zip(self.solution.iter()) {
+                            *w = *z * gain;
+                        }
+                    }
+                    // no usable covariance yet
+                    None => {
+                        for (w, d) in w.iter_mut().zip(d.iter()) {
+                            *w = d.scale(1.0 / n_mic as f32);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Solve a x = b in place (x in b) by Gaussian elimination with partial
+// pivoting; false when 'a' is singular.
+fn solve(a: &mut [Complex], b: &mut [Complex], n: usize) -> bool {
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|&i, &j| a[i * n + col].norm_sqr().total_cmp(&a[j * n + col].norm_sqr()))
+            .unwrap_or(col);
+        if a[pivot * n + col].norm_sqr() < 1e-30 {
+            return false;
+        }
+        if pivot != col {
+            for c in 0..n {
+                a.swap(pivot * n + c, col * n + c);
+            }
+            b.swap(pivot, col);
+        }
+        let inverse = a[col * n + col].recip();
+        for row in col + 1..n {
+            let factor = a[row * n + col] * inverse;
+            for c in col..n {
+                a[row * n + c] = a[row * n + c] - factor * a[col * n + c];
+            }
+            b[row] = b[row] - factor * b[col];
+        }
+    }
+    for row in (0..n).rev() {
+        let mut x = b[row];
+        for c in row + 1..n {
+            x = x - a[row * n + c] * b[c];
+        }
+        b[row] = x * a[row * n + row].recip();
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = 64;
+    const SAMPLE_RATE: usize = 16000;
+
+    // two mics on the x axis, 1 ms of sound apart
+    fn beamformer(method: BeamMethod, azimuth: f32) -> Beamformer {
+        let cfg = BeamformerConfig {
+            enabled: true,
+            mic_positions: vec![[0.1715, 0.0, 0.0], [-0.1715, 0.0, 0.0]],
+            ..BeamformerConfig::default()
+        };
+        let beams = vec![BeamConfig {
+            name: "test".to_string(),
+            method,
+            azimuth,
+            elevation: 0.0,
+        }];
+        Beamformer::new(&cfg, BLOCK, SAMPLE_RATE, 2, Arc::new(ArcSwap::from_pointee(beams)))
+    }
+
+    fn assert_close(a: Complex, b: Complex) {
+        assert!((a.re - b.re).abs() < 1e-4 && (a.im - b.im).abs() < 1e-4, "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn solves_complex_system() {
+        let n = 3;
+        let mut a = vec![
+            Complex::new(2.0, 1.0), Complex::new(0.0, -1.0), Complex::new(1.0, 0.0),
+            Complex::new(0.0, 0.0), Complex::new(0.0, 0.0), Complex::new(3.0, 0.5),
+            Complex::new(1.0, 1.0), Complex::new(4.0, 0.0), Complex::new(0.0, 2.0),
+        ];
+        let x = [Complex::new(1.0, -1.0), Complex::new(0.5, 2.0), Complex::new(-3.0, 0.0)];
+        let mut b: Vec<Complex> = (0..n)
+            .map(|row| {
+                let mut sum = Complex::ZERO;
+                for col in 0..n {
+                    sum += a[row * n + col] * x[col];
+                }
+                sum
+            })
+            .collect();
+        // the zero on the diagonal of the second row needs pivoting
+        assert!(solve(&mut a, &mut b, n));
+        for (solved, expected) in b.iter().zip(x.iter()) {
+            assert_close(*solved, *expected);
+        }
+
+        let mut singular = vec![
+            Complex::new(1.0, 0.0), Complex::new(2.0, 0.0),
+            Complex::new(2.0, 0.0), Complex::new(4.0, 0.0),
+        ];
+        let mut b = vec![Complex::new(1.0, 0.0); 2];
+        assert!(!solve(&mut singular, &mut b, 2));
+    }
+
+    #[test]
+    fn steers_by_the_path_difference() {
+        // looking along the x axis the first mic leads by 0.5 ms, the second lags
+        let along_x = beamformer(BeamMethod::DelayAndSum, 0.0);
+        for (k, d) in along_x.steering[0].iter().enumerate() {
+            let freq = (k * SAMPLE_RATE) as f32 / (2 * BLOCK) as f32;
+            let phase = 2.0 * PI * freq * 0.0005;
+            assert_close(d[0], Complex::from_phase(phase));
+            assert_close(d[1], Complex::from_phase(-phase));
+        }
+        // broadside both hear it at once
+        let broadside = beamformer(BeamMethod::DelayAndSum, 90.0);
+        for d in broadside.steering[0].iter() {
+            assert_close(d[0], Complex::new(1.0, 0.0));
+            assert_close(d[1], Complex::new(1.0, 0.0));
+        }
+    }
+
+    #[test]
+    fn passes_the_look_direction_a_block_late() {
+        for method in [BeamMethod::DelayAndSum, BeamMethod::Mvdr] {
+            let mut broadside = beamformer(method, 90.0);
+            let signal: Vec<f32> = (0..BLOCK * 40)
+                .map(|i| (2.0 * PI * 440.0 * i as f32 / SAMPLE_RATE as f32).sin() * 0.5)
+                .collect();
+            let mut beams = vec![vec![0.0; BLOCK]];
+            for (i, block) in signal.chunks(BLOCK).enumerate() {
+                broadside.process(&[block.to_vec(), block.to_vec()], &mut beams);
+                if i >= 2 {
+                    let expected = &signal[(i - 1) * BLOCK..i * BLOCK];
+                    for (out, s) in beams[0].iter().zip(expected.iter()) {
+                        assert!((out - s).abs() < 1e-3, "{method}: {out} != {s}");
+                    }
+                }
+            }
+        }
+    }
+}
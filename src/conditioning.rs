@@ -0,0 +1,271 @@
+use crate::config_file::{ConditioningConfig, HighPass};
+use arc_swap::ArcSwap;
+use std::f32::consts::PI;
+use std::fmt;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelSettings {
+    pub gain_db: f32,
+    pub mute: bool,
+    pub invert: bool,
+    pub high_pass: HighPass,
+    pub cutoff_hz: f32,
+}
+
+impl ChannelSettings {
+    fn gain(&self) -> f32 {
+        match (self.mute, self.invert) {
+            (true, _) => 0.0,
+            (false, invert) => {
+                let gain = 10_f32.powf(self.gain_db / 20.0);
+                if invert {
+                    -gain
+                } else {
+                    gain
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for ChannelSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "gain {} dB", self.gain_db)?;
+        if self.mute {
+            write!(f, " muted")?;
+        }
+        if self.invert {
+            write!(f, " inverted")?;
+        }
+        match self.high_pass {
+            HighPass::Off => write!(f, " high pass off"),
+            high_pass => write!(f, " high pass {} {} Hz", high_pass, self.cutoff_hz),
+        }
+    }
+}
+
+// Settings of every mic channel, the section values with the overrides of
+// 'channels' applied.
+pub fn channel_settings(cfg: &ConditioningConfig, n_mic: usize) -> Vec<ChannelSettings> {
+    let mut settings = vec![
+        ChannelSettings {
+            gain_db: cfg.gain_db,
+            mute: false,
+            invert: false,
+            high_pass: cfg.high_pass,
+            cutoff_hz: cfg.cutoff_hz,
+        };
+        n_mic
+    ];
+    for entry in cfg.channels.iter() {
+        let Some(channel) = settings.get_mut(entry.channel) else {
+            println!("conditioning: no mic channel {}, its settings are ignored", entry.channel);
+            continue;
+        };
+        channel.gain_db = entry.gain_db.unwrap_or(channel.gain_db);
+        channel.mute = entry.mute.unwrap_or(channel.mute);
+        channel.invert = entry.invert.unwrap_or(channel.invert);
+        channel.high_pass = entry.high_pass.unwrap_or(channel.high_pass);
+        channel.cutoff_hz = entry.cutoff_hz.unwrap_or(channel.cutoff_hz);
+    }
+    settings
+}
+
+// Transposed direct form II biquad.
+#[derive(Clone, Copy, Default)]
+struct Biquad {
+    b: [f32; 3],
+    a: [f32; 2],
+    z: [f32; 2],
+}
+
+impl Biquad {
+    // RBJ cookbook high pass
+    fn high_pass(cutoff_hz: f32, q: f32, sample_rate: usize) -> Biquad {
+        let w0 = 2.0 * PI * cutoff_hz / sample_rate as f32;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+        let a0 = 1.0 + alpha;
+        Biquad {
+            b: [(1.0 + cos) / 2.0 / a0, -(1.0 + cos) / a0, (1.0 + cos) / 2.0 / a0],
+            a: [-2.0 * cos / a0, (1.0 - alpha) / a0],
+            z: [0.0; 2],
+        }
+    }
+
+    // one pole DC blocker y = x - x1 + r y1, in biquad form
+    fn dc_blocker(cutoff_hz: f32, sample_rate: usize) -> Biquad {
+        let r = (-2.0 * PI * cutoff_hz / sample_rate as f32).exp();
+        Biquad {
+            b: [1.0, -1.0, 0.0],
+            a: [-r, 0.0],
+            z: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+struct ChannelState {
+    settings: Option<ChannelSettings>,
+    filters: Vec<Biquad>,
+    gain: f32,
+}
+
+// Applies the channel settings to the mic samples in place. Settings are
+// reloaded for every chunk; gain changes ramp over the chunk, filters keep
+// their state unless the filter type changes.
+pub struct Conditioner {
+    sample_rate: usize,
+    settings: Arc<ArcSwap<Vec<ChannelSettings>>>,
+    applied: Option<Arc<Vec<ChannelSettings>>>,
+    channels: Vec<ChannelState>,
+}
+
+impl Conditioner {
+    pub fn new(settings: Arc<ArcSwap<Vec<ChannelSettings>>>, sample_rate: usize, n_mic: usize) -> Conditioner {
+        Conditioner {
+            sample_rate,
+            settings,
+            applied: None,
+            channels: (0..n_mic)
+                .map(|_| ChannelState {
+                    settings: None,
+                    filters: Vec::new(),
+                    gain: 1.0,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn process(&mut self, mics: &mut [Vec<f32>], n: usize) {
+        let settings = self.settings.load_full();
+        let changed = !self.applied.as_ref().is_some_and(|applied| Arc::ptr_eq(applied, &settings));
+        for (channel, (state, mic)) in self.channels.iter_mut().zip(mics.iter_mut()).enumerate() {
+            let Some(&wanted) = settings.get(channel) else {
+                continue;
+            };
+            if changed && state.settings != Some(wanted) {
+                let same_filter = state
+                    .settings
+                    .is_some_and(|old| old.high_pass == wanted.high_pass);
+                let mut filters = filters_for(&wanted, self.sample_rate);
+                if same_filter {
+                    for (filter, old) in filters.iter_mut().zip(state.filters.iter()) {
+                        filter.z = old.z;
+                    }
+                }
+                if state.settings.is_none() {
+                    state.gain = wanted.gain();
+                }
+                state.filters = filters;
+                state.settings = Some(wanted);
+            }
+
+            let target = wanted.gain();
+            let step = (target - state.gain) / n.max(1) as f32;
+            for x in mic[..n].iter_mut() {
+                let mut y = *x;
+                for filter in state.filters.iter_mut() {
+                    y = filter.process(y);
+                }
+                state.gain += step;
+                *x = y * state.gain;
+            }
+            state.gain = target;
+        }
+        self.applied = Some(settings);
+    }
+}
+
+fn filters_for(settings: &ChannelSettings, sample_rate: usize) -> Vec<Biquad> {
+    let cutoff = settings.cutoff_hz.clamp(1.0, sample_rate as f32 * 0.45);
+    match settings.high_pass {
+        HighPass::Off => Vec::new(),
+        HighPass::Dc => vec![Biquad::dc_blocker(cutoff, sample_rate)],
+        HighPass::Butterworth2 => vec![Biquad::high_pass(cutoff, std::f32::consts::FRAC_1_SQRT_2, sample_rate)],
+        HighPass::Butterworth4 => vec![
+            Biquad::high_pass(cutoff, 0.541_196_1, sample_rate),
+            Biquad::high_pass(cutoff, 1.306_563, sample_rate),
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_file::ChannelOverride;
+
+    const SAMPLE_RATE: usize = 16000;
+
+    fn tone(hz: f32, n: usize) -> Vec<f32> {
+        (0..n).map(|i| (2.0 * PI * hz * i as f32 / SAMPLE_RATE as f32).sin()).collect()
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0, |peak, x| x.abs().max(peak))
+    }
+
+    fn conditioner(settings: Vec<ChannelSettings>) -> Conditioner {
+        let n_mic = settings.len();
+        Conditioner::new(Arc::new(ArcSwap::from_pointee(settings)), SAMPLE_RATE, n_mic)
+    }
+
+    #[test]
+    fn high_pass_attenuates_low_tone() {
+        for high_pass in [HighPass::Dc, HighPass::Butterworth2, HighPass::Butterworth4] {
+            let cfg = ConditioningConfig {
+                high_pass,
+                cutoff_hz: 100.0,
+                ..ConditioningConfig::default()
+            };
+            let mut conditioner = conditioner(channel_settings(&cfg, 2));
+            let mut mics = vec![tone(20.0, SAMPLE_RATE), tone(1000.0, SAMPLE_RATE)];
+            conditioner.process(&mut mics, SAMPLE_RATE);
+            // after the filters have settled
+            let low = peak(&mics[0][SAMPLE_RATE / 2..]);
+            let high = peak(&mics[1][SAMPLE_RATE / 2..]);
+            assert!(low < 0.25, "{high_pass}: 20 Hz peak {low}");
+            assert!(high > 0.9, "{high_pass}: 1 kHz peak {high}");
+        }
+
+        let mut conditioner = conditioner(channel_settings(&ConditioningConfig::default(), 1));
+        let mut mics = vec![tone(20.0, SAMPLE_RATE)];
+        conditioner.process(&mut mics, SAMPLE_RATE);
+        assert_eq!(mics[0], tone(20.0, SAMPLE_RATE));
+    }
+
+    #[test]
+    fn override_applies_to_its_channel_only() {
+        let overrides = |channel| ChannelOverride {
+            channel,
+            gain_db: Some(20.0 * 2_f32.log10()),
+            mute: None,
+            invert: Some(true),
+            high_pass: None,
+            cutoff_hz: None,
+        };
+        let cfg = ConditioningConfig {
+            channels: vec![overrides(1), overrides(5)],
+            ..ConditioningConfig::default()
+        };
+        let settings = channel_settings(&cfg, 3);
+        assert_eq!(settings.len(), 3);
+        assert_eq!(settings[0], settings[2]);
+        assert!(settings[1].invert && !settings[0].invert);
+
+        let mut conditioner = conditioner(settings);
+        let mut mics = vec![vec![0.25; 64]; 3];
+        conditioner.process(&mut mics, 64);
+        for (channel, mic) in mics.iter().enumerate() {
+            let expected = if channel == 1 { -0.5 } else { 0.25 };
+            assert!(mic.iter().all(|x| (x - expected).abs() < 1e-5), "channel {channel}: {mic:?}");
+        }
+    }
+}
@@ -1,9 +1,9 @@
 use serde::{Deserialize, Serialize};
 use std::{
+    fmt,
     fs,
     io::Write,
 };
-use toml;
 
 type Error = Box<dyn std::error::Error + Send + Sync>;
 
@@ -14,18 +14,108 @@ pub struct Config {
     pub audio_connection: AudioConnection,
     pub tcp_sender: TcpSenderConfig,
     pub tcp_receiver: TcpReceiverConfig,
+    #[serde(default)]
+    pub timing: TimingConfig,
+    #[serde(default)]
+    pub hub: HubConfig,
+    #[serde(default)]
+    pub routing: RoutingConfig,
+    #[serde(default)]
+    pub control: ControlConfig,
+    #[serde(default)]
+    pub processing: ProcessingConfig,
+    #[serde(default)]
+    pub conditioning: ConditioningConfig,
+    #[serde(default)]
+    pub aec: AecConfig,
+    #[serde(default)]
+    pub agc: AgcConfig,
+    #[serde(default)]
+    pub noise_suppression: NoiseSuppressionConfig,
+    #[serde(default)]
+    pub beamformer: BeamformerConfig,
+    #[serde(default)]
+    pub doa: DoaConfig,
+    #[serde(default)]
+    pub vad: VadConfig,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct MicConfig {
     pub start_jackd: bool,
+    // start jackd again if it exits while running
+    #[serde(default)]
+    pub restart_jackd: bool,
+    // restart the audio backend when the ALSA device is unplugged and back
+    #[serde(default)]
+    pub watch_device: bool,
     pub driver: String,
     pub device_name: String,
     pub device_id: usize,
+    // rate the audio device runs at
     pub sample_rate: usize,
+    // rate of the streams sent and received, 0 for sample_rate; capture is
+    // resampled to it and received streams back to sample_rate
+    #[serde(default)]
+    pub stream_rate: usize,
     pub period: usize,
     pub n_period: usize,
     pub n_channel: usize,
+    // capture ports of the stream channels, in stream order; when given,
+    // n_channel follows the number of entries
+    #[serde(default)]
+    pub channels: Vec<ChannelMapping>,
+    // capture the first n_channel ports of other JACK clients matching this
+    // regex, e.g. "beamformer:out_.*", instead of the physical ports;
+    // ports that appear later are connected when they show up
+    #[serde(default)]
+    pub capture_pattern: String,
+    // more capture devices, bridged into JACK with drift compensation; their
+    // channels follow the n_channel (or mapped) channels of the main device
+    #[serde(default)]
+    pub extra_devices: Vec<ExtraDevice>,
+}
+
+impl MicConfig {
+    pub fn stream_rate(&self) -> usize {
+        if self.stream_rate == 0 {
+            self.sample_rate
+        } else {
+            self.stream_rate
+        }
+    }
+
+    // Channels captured from all devices together.
+    pub fn total_channels(&self) -> usize {
+        self.n_channel + self.extra_devices.iter().map(|device| device.n_channel).sum::<usize>()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ExtraDevice {
+    pub name: String,
+    // ALSA device, e.g. "hw:UAC2"
+    pub device_name: String,
+    pub n_channel: usize,
+    #[serde(default)]
+    pub bridge: BridgeKind,
+}
+
+impl ExtraDevice {
+    // Name of the JACK client bridging the device.
+    pub fn jack_name(&self) -> String {
+        format!("mic2sock_{}", self.name)
+    }
+}
+
+// Program that resamples a device into the JACK clock domain.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BridgeKind {
+    #[default]
+    Zita,
+    #[serde(rename = "alsa_in")]
+    AlsaIn,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -33,6 +123,50 @@ pub struct SpeakerConfig {
     pub use_alsa_out: bool,
     pub device_name: String,
     pub n_channel: usize,
+    // playback ports of the received channels, in stream order
+    #[serde(default)]
+    pub channels: Vec<ChannelMapping>,
+    // length of the fade in when playback starts, of the fade out when the
+    // received stream runs dry and of the turn when it comes back mid fade;
+    // playback is held back by as much to have samples to fade out
+    #[serde(default = "default_fade_ms")]
+    pub fade_ms: usize,
+}
+
+fn default_fade_ms() -> usize {
+    10
+}
+
+// JACK port of one stream channel; set one of 'port', 'pattern' or 'index'.
+// Without a mapping, physical ports with "capture"/"playback" in their name
+// are used in enumeration order.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ChannelMapping {
+    pub name: String,
+    // full port name, e.g. "system:capture_3"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<String>,
+    // JACK port regex; the first matching port is used
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    // ALSA channel, i.e. the index among the physical ports of the device
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+}
+
+impl fmt::Display for ChannelMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(port) = &self.port {
+            write!(f, "{}", port)
+        } else if let Some(pattern) = &self.pattern {
+            write!(f, "/{}/", pattern)
+        } else if let Some(index) = self.index {
+            write!(f, "physical #{}", index)
+        } else {
+            write!(f, "unmapped")
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -42,21 +176,598 @@ pub struct AudioConnection {
     pub speaker_idx: usize,
 }
 
+// Monitoring mix played on the speaker outputs, applied in the audio callback.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct RoutingConfig {
+    // when empty: received channel i to speaker i, plus the audio_connection link
+    pub routes: Vec<Route>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteSource {
+    // a captured mic channel, in stream order
+    Mic,
+    // a channel of the received stream
+    Recv,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Route {
+    pub source: RouteSource,
+    pub channel: usize,
+    // speaker output
+    pub output: usize,
+    // linear
+    #[serde(default = "unity_gain")]
+    pub gain: f32,
+}
+
+fn unity_gain() -> f32 {
+    1.0
+}
+
+// Line based TCP control interface for runtime changes; see control.rs.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ControlConfig {
+    // 0 disables the control server
+    pub port: usize,
+    pub bind: String,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        ControlConfig {
+            port: 0,
+            bind: "127.0.0.1".to_string(),
+        }
+    }
+}
+
+// Processing of the mic channels in the sender, before they are quantized.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ProcessingConfig {
+    // samples per processing block, a power of two; adds up to one block of
+    // latency when any processing is enabled
+    pub block_len: usize,
+}
+
+impl Default for ProcessingConfig {
+    fn default() -> Self {
+        ProcessingConfig { block_len: 128 }
+    }
+}
+
+// Gain, polarity and high-pass filtering of each mic channel, first thing in
+// the capture path. The section sets every channel; 'channels' entries
+// override single fields of single channels.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ConditioningConfig {
+    pub gain_db: f32,
+    pub high_pass: HighPass,
+    pub cutoff_hz: f32,
+    pub channels: Vec<ChannelOverride>,
+}
+
+impl Default for ConditioningConfig {
+    fn default() -> Self {
+        ConditioningConfig {
+            gain_db: 0.0,
+            high_pass: HighPass::Off,
+            cutoff_hz: 20.0,
+            channels: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HighPass {
+    #[default]
+    Off,
+    // one pole DC blocker
+    Dc,
+    Butterworth2,
+    Butterworth4,
+}
+
+impl fmt::Display for HighPass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HighPass::Off => write!(f, "off"),
+            HighPass::Dc => write!(f, "dc"),
+            HighPass::Butterworth2 => write!(f, "butterworth2"),
+            HighPass::Butterworth4 => write!(f, "butterworth4"),
+        }
+    }
+}
+
+impl std::str::FromStr for HighPass {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<HighPass, Error> {
+        match s {
+            "off" => Ok(HighPass::Off),
+            "dc" => Ok(HighPass::Dc),
+            "butterworth2" => Ok(HighPass::Butterworth2),
+            "butterworth4" => Ok(HighPass::Butterworth4),
+            _ => Err("high pass must be off, dc, butterworth2 or butterworth4".into()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChannelOverride {
+    // mic channel in stream order
+    pub channel: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gain_db: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mute: Option<bool>,
+    // flip the polarity
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub invert: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub high_pass: Option<HighPass>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cutoff_hz: Option<f32>,
+}
+
+// Acoustic echo cancellation with the received channels as reference.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct AecConfig {
+    pub enabled: bool,
+    // length of the room response the filter covers
+    pub tail_ms: usize,
+    // largest playback to capture delay searched for
+    pub max_delay_ms: usize,
+    // NLMS step size, 0..1
+    pub step: f32,
+}
+
+impl Default for AecConfig {
+    fn default() -> Self {
+        AecConfig {
+            enabled: false,
+            tail_ms: 128,
+            max_delay_ms: 200,
+            step: 0.5,
+        }
+    }
+}
+
+// Beams steered over the mic array, sent as extra channels.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct BeamformerConfig {
+    pub enabled: bool,
+    // [x, y, z] in metres of the first mic channels, in stream order; beams
+    // are formed from these channels only
+    pub mic_positions: Vec<[f32; 3]>,
+    pub speed_of_sound: f32,
+    // 0: beams follow the other channels in the packets of the main stream;
+    // otherwise they are served as a stream of their own on this port
+    pub listen_port: usize,
+    pub max_clients: usize,
+    // MVDR: averaging time of the spatial covariance, how often the weights
+    // are recomputed, and diagonal loading relative to the mean mic power
+    pub mvdr_average_ms: usize,
+    pub mvdr_update_ms: usize,
+    pub mvdr_loading: f32,
+    pub beams: Vec<BeamConfig>,
+}
+
+impl BeamformerConfig {
+    pub fn n_beam(&self) -> usize {
+        if self.enabled && !self.mic_positions.is_empty() {
+            self.beams.len()
+        } else {
+            0
+        }
+    }
+}
+
+impl Default for BeamformerConfig {
+    fn default() -> Self {
+        BeamformerConfig {
+            enabled: false,
+            mic_positions: Vec::new(),
+            speed_of_sound: 343.0,
+            listen_port: 0,
+            max_clients: 100,
+            mvdr_average_ms: 500,
+            mvdr_update_ms: 100,
+            mvdr_loading: 0.01,
+            beams: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BeamConfig {
+    pub name: String,
+    #[serde(default)]
+    pub method: BeamMethod,
+    // look direction in degrees; azimuth counterclockwise from the x axis,
+    // elevation up from the x-y plane
+    pub azimuth: f32,
+    #[serde(default)]
+    pub elevation: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BeamMethod {
+    #[default]
+    DelayAndSum,
+    Mvdr,
+}
+
+impl fmt::Display for BeamMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BeamMethod::DelayAndSum => write!(f, "delay_and_sum"),
+            BeamMethod::Mvdr => write!(f, "mvdr"),
+        }
+    }
+}
+
+impl fmt::Display for BeamConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} azimuth {} elevation {}",
+            self.name, self.method, self.azimuth, self.elevation
+        )
+    }
+}
+
+// Direction of arrival estimation (SRP-PHAT) over the mics placed by
+// beamformer.mic_positions, published as JSON lines.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct DoaConfig {
+    pub enabled: bool,
+    pub listen_port: usize,
+    pub max_clients: usize,
+    // samples per estimate, rounded up to whole packets
+    pub window_ms: usize,
+    // search grid in degrees
+    pub azimuth_step: f32,
+    pub elevation_min: f32,
+    pub elevation_max: f32,
+    pub elevation_step: f32,
+}
+
+impl Default for DoaConfig {
+    fn default() -> Self {
+        DoaConfig {
+            enabled: false,
+            listen_port: 7995,
+            max_clients: 10,
+            window_ms: 100,
+            azimuth_step: 5.0,
+            elevation_min: 0.0,
+            elevation_max: 0.0,
+            elevation_step: 10.0,
+        }
+    }
+}
+
+// Voice activity detection on one channel, flagged in the packet header
+// (FLAG_SPEECH, needs tcp_sender.header_len >= 38).
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct VadConfig {
+    pub enabled: bool,
+    pub source: VadSource,
+    pub channel: usize,
+    // speech must be this much above the tracked noise floor
+    pub threshold_db: f32,
+    // and at least this loud, relative to full scale
+    pub min_level_db: f32,
+    // packets stay flagged this long after the last speech
+    pub hang_ms: usize,
+    // port serving only the packets of speech segments; 0 disables it
+    pub speech_port: usize,
+    pub max_clients: usize,
+    // packets sent ahead of each segment on the speech port
+    pub pre_roll_ms: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum VadSource {
+    // a mic channel, in stream order
+    #[default]
+    Mic,
+    Beam,
+}
+
+impl Default for VadConfig {
+    fn default() -> Self {
+        VadConfig {
+            enabled: false,
+            source: VadSource::Mic,
+            channel: 0,
+            threshold_db: 9.0,
+            min_level_db: -60.0,
+            hang_ms: 300,
+            speech_port: 0,
+            max_clients: 10,
+            pre_roll_ms: 100,
+        }
+    }
+}
+
+// Spectral noise suppression (Wiener gain, decision directed SNR, noise
+// tracked as the minimum of the smoothed spectrum) on selected channels.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct NoiseSuppressionConfig {
+    pub enabled: bool,
+    // mic channels and beams to suppress noise on, by index
+    pub mics: Vec<usize>,
+    pub beams: Vec<usize>,
+    pub max_attenuation_db: f32,
+    // how fast the noise estimate may rise, dB per second
+    pub noise_rise_db: f32,
+    // port serving the mic channels as captured, before any processing, in
+    // packets laid out like the main stream without beams; 0 disables it
+    pub raw_port: usize,
+    pub max_clients: usize,
+}
+
+impl Default for NoiseSuppressionConfig {
+    fn default() -> Self {
+        NoiseSuppressionConfig {
+            enabled: false,
+            mics: Vec::new(),
+            beams: Vec::new(),
+            max_attenuation_db: 20.0,
+            noise_rise_db: 3.0,
+            raw_port: 0,
+            max_clients: 10,
+        }
+    }
+}
+
+// Automatic gain control and a look-ahead limiter, the last stage before the
+// channels are quantized.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct AgcConfig {
+    pub enabled: bool,
+    // one gain for all mic channels, keeping their level differences;
+    // otherwise every channel has its own. Beams always have their own.
+    pub linked: bool,
+    // RMS level the gain aims for, dB relative to full scale
+    pub target_db: f32,
+    pub max_gain_db: f32,
+    pub min_gain_db: f32,
+    // how fast the gain falls and rises
+    pub attack_ms: usize,
+    pub release_ms: usize,
+    // the gain is held while the level is below this
+    pub gate_db: f32,
+    pub limiter: bool,
+    // peak ceiling, dB relative to full scale
+    pub limit_db: f32,
+    // latency the limiter adds to see peaks coming
+    pub lookahead_ms: usize,
+    pub limiter_release_ms: usize,
+}
+
+impl Default for AgcConfig {
+    fn default() -> Self {
+        AgcConfig {
+            enabled: false,
+            linked: true,
+            target_db: -20.0,
+            max_gain_db: 30.0,
+            min_gain_db: -10.0,
+            attack_ms: 50,
+            release_ms: 1000,
+            gate_db: -55.0,
+            limiter: false,
+            limit_db: -1.0,
+            lookahead_ms: 5,
+            limiter_release_ms: 50,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct TcpSenderConfig {
     pub listen_port: usize,
     pub max_clients: usize,
     pub header_len: usize,
     pub sample_per_packet: usize,
+    // port serving the stream description (TOML) to every client; 0 disables it
+    #[serde(default)]
+    pub info_port: usize,
+    // collectors ("host:port") the packet stream is pushed to, besides listening
+    #[serde(default)]
+    pub push_targets: Vec<String>,
+    #[serde(default)]
+    pub push_reconnect: ReconnectConfig,
+    // format of the samples in the packets of every stream sent
+    #[serde(default)]
+    pub sample_format: SampleFormat,
+    // TPDF dither when quantizing to i16
+    #[serde(default)]
+    pub dither: bool,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct TcpReceiverConfig {
+    // "connect": dial out to host:port; "listen": accept the stream on port
+    #[serde(default)]
+    pub mode: ReceiverMode,
     pub host: String,
     pub port: usize,
     pub header_len: usize,
     pub n_channel: usize,
     pub sample_per_packet: usize,
+    // what to do when a second peer connects in listen mode
+    #[serde(default)]
+    pub on_new_sender: SenderPolicy,
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+    #[serde(default)]
+    pub sample_format: SampleFormat,
+}
+
+// Sample format of the packets, little endian.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleFormat {
+    #[default]
+    I16,
+    // packed in 3 bytes
+    I24,
+    F32,
+}
+
+impl SampleFormat {
+    pub fn bytes(&self) -> usize {
+        match self {
+            SampleFormat::I16 => 2,
+            SampleFormat::I24 => 3,
+            SampleFormat::F32 => 4,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ReceiverMode {
+    #[default]
+    Connect,
+    Listen,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SenderPolicy {
+    #[default]
+    Reject,
+    Preempt,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ReconnectConfig {
+    pub initial_interval_ms: u64,
+    pub max_interval_ms: u64,
+    pub multiplier: f64,
+    // fraction of the delay that is randomized, 0.0 ~ 1.0
+    pub jitter: f64,
+    // keep retrying when the host name cannot be resolved (e.g. network not up yet)
+    pub retry_on_dns_failure: bool,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial_interval_ms: 500,
+            max_interval_ms: 30000,
+            multiplier: 2.0,
+            jitter: 0.2,
+            retry_on_dns_failure: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct TimingConfig {
+    // clock carried next to the wall clock in the packet header
+    pub reference_clock: ReferenceClockKind,
+    pub ptp_device: String,
+    pub sync_interval_ms: u64,
+    // 0 disables the offset/drift report
+    pub report_interval_s: u64,
+    pub drift_window_s: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ReferenceClockKind {
+    #[default]
+    Realtime,
+    Tai,
+    Ptp,
+}
+
+impl Default for TimingConfig {
+    fn default() -> Self {
+        TimingConfig {
+            reference_clock: ReferenceClockKind::Realtime,
+            ptp_device: "/dev/ptp0".to_string(),
+            sync_interval_ms: 1000,
+            report_interval_s: 60,
+            drift_window_s: 120,
+        }
+    }
+}
+
+// Hub mode relays and merges the streams of other mic2sock instances instead
+// of capturing audio itself.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct HubConfig {
+    pub enabled: bool,
+    // port serving the merged stream
+    pub listen_port: usize,
+    pub max_clients: usize,
+    // device_id written into merged packets
+    pub device_id: usize,
+    // packet format shared by all upstreams; header_len must match their
+    // tcp_sender.header_len and defaults to the full header
+    pub header_len: usize,
+    pub sample_per_packet: usize,
+    pub sample_rate: usize,
+    pub sample_format: SampleFormat,
+    // how long a slot waits for a late upstream before it is sent with silence
+    pub max_wait_ms: u64,
+    pub upstreams: Vec<HubUpstream>,
+    pub reconnect: ReconnectConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HubUpstream {
+    pub host: String,
+    pub port: usize,
+    pub n_channel: usize,
+    // port re-serving this upstream unchanged; 0 to only merge it
+    #[serde(default)]
+    pub listen_port: usize,
+}
+
+impl Default for HubConfig {
+    fn default() -> Self {
+        HubConfig {
+            enabled: false,
+            listen_port: 8998,
+            max_clients: 100,
+            device_id: 255,
+            header_len: 38,
+            sample_per_packet: 160,
+            sample_rate: 16000,
+            sample_format: SampleFormat::I16,
+            max_wait_ms: 40,
+            upstreams: Vec::new(),
+            reconnect: ReconnectConfig {
+                retry_on_dns_failure: true,
+                ..ReconnectConfig::default()
+            },
+        }
+    }
 }
 
 impl Config {
@@ -69,18 +780,26 @@ impl Config {
                 let conf = Config {
                     mic: MicConfig {
                         start_jackd: true,
+                        restart_jackd: false,
+                        watch_device: false,
                         driver: "alsa".to_string(),
                         device_name: "hw:RASPZX16ch".to_string(),
                         device_id: 0,
                         sample_rate: 16000,
+                        stream_rate: 0,
                         period: 32,
                         n_period: 4,
                         n_channel: 16,
+                        channels: Vec::new(),
+                        capture_pattern: String::new(),
+                        extra_devices: Vec::new(),
                     },
                     speaker: SpeakerConfig { 
                         use_alsa_out: false,
                         device_name: "plughw:Device".to_string(),
                         n_channel: 1,
+                        channels: Vec::new(),
+                        fade_ms: default_fade_ms(),
                     },
                     audio_connection: AudioConnection {
                         connect_mic_speaker: false,
@@ -92,19 +811,41 @@ impl Config {
                         max_clients: 100,
                         header_len: 12,
                         sample_per_packet: 160,
+                        info_port: 0,
+                        push_targets: Vec::new(),
+                        push_reconnect: ReconnectConfig::default(),
+                        sample_format: SampleFormat::I16,
+                        dither: false,
                     },
                     tcp_receiver: TcpReceiverConfig {
+                        mode: ReceiverMode::Connect,
                         host: "none".to_string(),
                         port: 4000,
                         header_len: 16,
                         n_channel: 1,
-                        sample_per_packet: 160
+                        sample_per_packet: 160,
+                        on_new_sender: SenderPolicy::Reject,
+                        reconnect: ReconnectConfig::default(),
+                        sample_format: SampleFormat::I16,
                     },
+                    timing: TimingConfig::default(),
+                    hub: HubConfig::default(),
+                    routing: RoutingConfig::default(),
+                    control: ControlConfig::default(),
+                    processing: ProcessingConfig::default(),
+                    conditioning: ConditioningConfig::default(),
+                    aec: AecConfig::default(),
+                    agc: AgcConfig::default(),
+                    noise_suppression: NoiseSuppressionConfig::default(),
+                    beamformer: BeamformerConfig::default(),
+                    doa: DoaConfig::default(),
+                    vad: VadConfig::default(),
                 };
                 let toml = toml::to_string(&conf).unwrap();
                 let mut f = fs::OpenOptions::new()
                     .write(true)
                     .create(true)
+                    .truncate(true)
                     .open("conf.toml")
                     .unwrap();
                 f.write_all(toml.as_bytes()).unwrap();
@@ -115,7 +856,13 @@ impl Config {
 
     fn read_conf_file() -> Result<Config, Error> {
         let contents = fs::read_to_string("config.toml")?;
-        let conf: Config = toml::from_str(&contents)?;
+        let mut conf: Config = toml::from_str(&contents)?;
+        if !conf.mic.channels.is_empty() {
+            conf.mic.n_channel = conf.mic.channels.len();
+        }
+        if !conf.speaker.channels.is_empty() {
+            conf.speaker.n_channel = conf.speaker.channels.len();
+        }
         Ok(conf)
     }
 }
@@ -0,0 +1,239 @@
+use crate::conditioning::ChannelSettings;
+use crate::config_file::{BeamConfig, ControlConfig, HighPass, Route, RouteSource};
+use crate::routing::RoutingMatrix;
+use arc_swap::ArcSwap;
+use std::future::Future;
+use std::str::{FromStr, SplitWhitespace};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, Duration};
+
+const HELP: &str = "\
+routes                                  list the monitoring routes
+route <mic|recv> <channel> <output> [gain]  add a route or change its gain
+unroute <mic|recv> <channel> <output>   remove a route
+clear_routes                            remove all routes
+beams                                   list the beams
+steer <beam> <azimuth> [elevation]      point a beam (name or index), in degrees
+channels                                list the mic channel settings
+gain <channel|all> <dB>                 set the gain of mic channels
+mute <channel|all> / unmute <channel|all>
+invert <channel|all> <on|off>           flip the polarity
+highpass <channel|all> <off|dc|butterworth2|butterworth4> [cutoff Hz]";
+
+// Settings that can be changed while running. Every command is one line;
+// the reply is zero or more lines followed by "ok" or "error: <reason>".
+pub struct Controls {
+    pub routing: Arc<ArcSwap<RoutingMatrix>>,
+    pub beams: Arc<ArcSwap<Vec<BeamConfig>>>,
+    pub channels: Arc<ArcSwap<Vec<ChannelSettings>>>,
+}
+
+impl Controls {
+    fn execute(&self, line: &str) -> crate::Result<String> {
+        let mut args = line.split_whitespace();
+        let Some(command) = args.next() else {
+            return Ok(String::new());
+        };
+        match command {
+            "help" => Ok(HELP.to_string()),
+            "routes" => Ok(self
+                .routing
+                .load()
+                .routes()
+                .iter()
+                .map(|route| route.to_string())
+                .collect::<Vec<_>>()
+                .join("\n")),
+            "route" => {
+                let route = Route {
+                    source: parse_source(&mut args)?,
+                    channel: next_arg(&mut args, "channel")?,
+                    output: next_arg(&mut args, "output")?,
+                    gain: args.next().map_or(Ok(1.0), |gain| gain.parse())?,
+                };
+                self.update_routing(|matrix| matrix.set(route))?;
+                Ok(String::new())
+            }
+            "unroute" => {
+                let source = parse_source(&mut args)?;
+                let channel = next_arg(&mut args, "channel")?;
+                let output = next_arg(&mut args, "output")?;
+                self.update_routing(|matrix| match matrix.remove(source, channel, output) {
+                    true => Ok(()),
+                    false => Err("no such route".into()),
+                })?;
+                Ok(String::new())
+            }
+            "clear_routes" => {
+                self.update_routing(|matrix| {
+                    matrix.clear();
+                    Ok(())
+                })?;
+                Ok(String::new())
+            }
+            "beams" => Ok(self
+                .beams
+                .load()
+                .iter()
+                .enumerate()
+                .map(|(i, beam)| format!("{} {}", i, beam))
+                .collect::<Vec<_>>()
+                .join("\n")),
+            "steer" => {
+                let name = args.next().ok_or("missing beam")?;
+                let azimuth: f32 = next_arg(&mut args, "azimuth")?;
+                let elevation = args.next().map(|elevation| elevation.parse::<f32>()).transpose()?;
+                let mut beams = Vec::clone(&self.beams.load());
+                let beam = beams
+                    .iter_mut()
+                    .enumerate()
+                    .find(|(i, beam)| beam.name == name || i.to_string() == name)
+                    .map(|(_, beam)| beam)
+                    .ok_or("no such beam")?;
+                beam.azimuth = azimuth;
+                if let Some(elevation) = elevation {
+                    beam.elevation = elevation;
+                }
+                self.beams.store(Arc::new(beams));
+                Ok(String::new())
+            }
+            "channels" => Ok(self
+                .channels
+                .load()
+                .iter()
+                .enumerate()
+                .map(|(i, settings)| format!("{} {}", i, settings))
+                .collect::<Vec<_>>()
+                .join("\n")),
+            "gain" => {
+                let selected = args.next();
+                let gain_db: f32 = next_arg(&mut args, "gain")?;
+                self.update_channels(selected, |settings| settings.gain_db = gain_db)?;
+                Ok(String::new())
+            }
+            "mute" | "unmute" => {
+                let mute = command == "mute";
+                self.update_channels(args.next(), |settings| settings.mute = mute)?;
+                Ok(String::new())
+            }
+            "invert" => {
+                let selected = args.next();
+                let invert = match args.next() {
+                    Some("on") => true,
+                    Some("off") => false,
+                    _ => return Err("invert needs on or off".into()),
+                };
+                self.update_channels(selected, |settings| settings.invert = invert)?;
+                Ok(String::new())
+            }
+            "highpass" => {
+                let selected = args.next();
+                let high_pass: HighPass = args.next().ok_or("missing filter")?.parse()?;
+                let cutoff_hz = args.next().map(|cutoff| cutoff.parse::<f32>()).transpose()?;
+                self.update_channels(selected, |settings| {
+                    settings.high_pass = high_pass;
+                    settings.cutoff_hz = cutoff_hz.unwrap_or(settings.cutoff_hz);
+                })?;
+                Ok(String::new())
+            }
+            _ => Err(format!("unknown command '{}', try help", command).into()),
+        }
+    }
+
+    // 'selected' is a mic channel index or "all".
+    fn update_channels(
+        &self,
+        selected: Option<&str>,
+        change: impl Fn(&mut ChannelSettings),
+    ) -> crate::Result<()> {
+        let mut channels = Vec::clone(&self.channels.load());
+        match selected {
+            Some("all") => channels.iter_mut().for_each(change),
+            Some(channel) => {
+                let channel: usize = channel.parse().map_err(|_| "invalid channel")?;
+                change(channels.get_mut(channel).ok_or("no such channel")?);
+            }
+            None => return Err("missing channel".into()),
+        }
+        self.channels.store(Arc::new(channels));
+        Ok(())
+    }
+
+    fn update_routing(
+        &self,
+        change: impl FnOnce(&mut RoutingMatrix) -> crate::Result<()>,
+    ) -> crate::Result<()> {
+        let mut matrix = RoutingMatrix::clone(&self.routing.load());
+        change(&mut matrix)?;
+        self.routing.store(Arc::new(matrix));
+        Ok(())
+    }
+}
+
+fn next_arg<T: FromStr>(args: &mut SplitWhitespace, what: &str) -> crate::Result<T> {
+    args.next()
+        .and_then(|arg| arg.parse().ok())
+        .ok_or_else(|| format!("missing or invalid {}", what).into())
+}
+
+fn parse_source(args: &mut SplitWhitespace) -> crate::Result<RouteSource> {
+    match args.next() {
+        Some("mic") => Ok(RouteSource::Mic),
+        Some("recv") => Ok(RouteSource::Recv),
+        _ => Err("source must be mic or recv".into()),
+    }
+}
+
+async fn handle_connection(socket: TcpStream, controls: Arc<Controls>) -> crate::Result<()> {
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let mut reply = match controls.execute(&line) {
+            Ok(output) if output.is_empty() => "ok\n".to_string(),
+            Ok(output) => format!("{}\nok\n", output),
+            Err(err) => format!("error: {}\n", err),
+        };
+        if line.trim().is_empty() {
+            reply.clear();
+        }
+        writer.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+// Run the control server; SIGINT ('tokio::signal::ctrl_c()') can be used as 'shutdown' argument.
+pub async fn start_control_server(cfg: ControlConfig, controls: Arc<Controls>, shutdown: impl Future) {
+    let listener = match TcpListener::bind(format!("{}:{}", cfg.bind, cfg.port)).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            println!("Error! Failed to listen on control port {}. {}", cfg.port, err);
+            return;
+        }
+    };
+    println!("control on {}:{}", cfg.bind, cfg.port);
+
+    tokio::select! {
+        _ = async {
+            loop {
+                match listener.accept().await {
+                    Ok((socket, addr)) => {
+                        println!("control connection from {}", addr);
+                        let controls = controls.clone();
+                        tokio::spawn(async move {
+                            if let Err(err) = handle_connection(socket, controls).await {
+                                println!("Error! Control connection error. {}", err);
+                            }
+                        });
+                    }
+                    Err(err) => {
+                        println!("Error! Failed to accept control connection. {}", err);
+                        time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        } => {}
+        _ = shutdown => {}
+    }
+}
@@ -0,0 +1,273 @@
+use crate::config_file::{BeamformerConfig, DoaConfig};
+use crate::fft::{Complex, Fft};
+use crate::packet_header::PacketHeader;
+use crossbeam::channel::Receiver;
+use tokio::sync::broadcast;
+
+// cross-correlations are interpolated by this factor before lags are read
+const UPSAMPLE: usize = 4;
+
+#[derive(Clone, Copy, Debug)]
+pub struct DoaEstimate {
+    pub azimuth: f32,
+    pub elevation: f32,
+    // mean PHAT correlation of the mic pairs at the peak, about 1 for a
+    // single source without noise or reverberation
+    pub strength: f32,
+    // mean mic power in dB relative to full scale
+    pub level_db: f32,
+}
+
+// SRP-PHAT: the steered response power of a direction is the sum over all mic
+// pairs of their PHAT weighted cross-correlation (GCC-PHAT) at the lag the
+// direction gives the pair; the estimate is the grid direction with the most.
+pub struct DoaEstimator {
+    window: usize,
+    fft: Fft,
+    upsampled_fft: Fft,
+    pairs: Vec<(usize, usize)>,
+    // (azimuth, elevation) in degrees
+    grid: Vec<(f32, f32)>,
+    // [grid point][pair] index into the upsampled correlations
+    lags: Vec<Vec<usize>>,
+    buffers: Vec<Vec<f32>>,
+    spectra: Vec<Vec<Complex>>,
+    scratch: Vec<Complex>,
+    // [pair]
+    correlations: Vec<Vec<f32>>,
+}
+
+impl DoaEstimator {
+    // Uses the mics placed in 'array', at most 'n_mic'; None with fewer
+    // than two.
+    pub fn new(
+        cfg: &DoaConfig,
+        array: &BeamformerConfig,
+        n_mic: usize,
+        sample_rate: usize,
+        window: usize,
+    ) -> Option<DoaEstimator> {
+        let positions = &array.mic_positions[..array.mic_positions.len().min(n_mic)];
+        if positions.len() < 2 || window == 0 {
+            return None;
+        }
+        let n_fft = 2 * window.next_power_of_two();
+        let n_upsampled = n_fft * UPSAMPLE;
+
+        let mut grid = Vec::new();
+        let mut elevation = cfg.elevation_min;
+        loop {
+            let mut azimuth = 0.0;
+            while azimuth < 360.0 {
+                grid.push((azimuth, elevation));
+                azimuth += cfg.azimuth_step.max(0.1);
+            }
+            elevation += cfg.elevation_step.max(0.1);
+            if elevation > cfg.elevation_max {
+                break;
+            }
+        }
+
+        let mut pairs = Vec::new();
+        for i in 0..positions.len() {
+            for j in i + 1..positions.len() {
+                pairs.push((i, j));
+            }
+        }
+        let speed_of_sound = array.speed_of_sound;
+        let lags = grid
+            .iter()
+            .map(|&(azimuth, elevation)| {
+                let (azimuth, elevation) = (azimuth.to_radians(), elevation.to_radians());
+                let look = [
+                    elevation.cos() * azimuth.cos(),
+                    elevation.cos() * azimuth.sin(),
+                    elevation.sin(),
+                ];
+                // how much earlier a mic hears the direction than the origin
+                let lead = |p: &[f32; 3]| (p[0] * look[0] + p[1] * look[1] + p[2] * look[2]) / speed_of_sound;
+                pairs
+                    .iter()
+                    .map(|&(i, j)| {
+                        let lag = (lead(&positions[j]) - lead(&positions[i])) * (sample_rate * UPSAMPLE) as f32;
+                        (lag.round() as i64).rem_euclid(n_upsampled as i64) as usize
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Some(DoaEstimator {
+            window,
+            fft: Fft::new(n_fft),
+            upsampled_fft: Fft::new(n_upsampled),
+            correlations: vec![vec![0.0; n_upsampled]; pairs.len()],
+            pairs,
+            grid,
+            lags,
+            buffers: vec![Vec::with_capacity(window); positions.len()],
+            spectra: vec![vec![Complex::ZERO; n_fft]; positions.len()],
+            scratch: vec![Complex::ZERO; n_upsampled],
+        })
+    }
+
+    // Drop what was collected of the current window.
+    pub fn reset(&mut self) {
+        for buffer in self.buffers.iter_mut() {
+            buffer.clear();
+        }
+    }
+
+    // Add the samples of every mic; returns an estimate when a window is full.
+    pub fn push(&mut self, mics: &[Vec<f32>]) -> Option<DoaEstimate> {
+        for (buffer, mic) in self.buffers.iter_mut().zip(mics.iter()) {
+            buffer.extend_from_slice(mic);
+        }
+        if self.buffers[0].len() < self.window {
+            return None;
+        }
+        let estimate = self.estimate();
+        self.reset();
+        Some(estimate)
+    }
+
+    fn estimate(&mut self) -> DoaEstimate {
+        let n_fft = self.spectra[0].len();
+        let n_upsampled = self.scratch.len();
+        let mut power = 0.0;
+        for (buffer, spectrum) in self.buffers.iter().zip(self.spectra.iter_mut()) {
+            power += buffer.iter().map(|x| x * x).sum::<f32>() / buffer.len() as f32;
+            self.fft.forward_real(buffer, spectrum);
+        }
+        let level_db = 10.0 * (power / self.buffers.len() as f32 + 1e-20).log10();
+
+        for (&(i, j), correlation) in self.pairs.iter().zip(self.correlations.iter_mut()) {
+            // the positive and negative frequencies of the pair's cross spectrum,
+            // zero padded in between
+            self.scratch.fill(Complex::ZERO);
+            for k in 0..n_fft {
+                let cross = self.spectra[i][k] * self.spectra[j][k].conj();
+                let magnitude = cross.norm_sqr().sqrt();
+                if magnitude < 1e-20 {
+                    continue;
+                }
+                let bin = if k <= n_fft / 2 { k } else { n_upsampled - (n_fft - k) };
+                self.scratch[bin] = cross.scale(1.0 / magnitude);
+            }
+            self.upsampled_fft.inverse(&mut self.scratch);
+            for (c, x) in correlation.iter_mut().zip(self.scratch.iter()) {
+                *c = x.re;
+            }
+        }
+
+        let (mut best, mut best_power) = (0, f32::MIN);
+        for (point, lags) in self.lags.iter().enumerate() {
+            let power: f32 = lags
+                .iter()
+                .zip(self.correlations.iter())
+                .map(|(&lag, correlation)| correlation[lag])
+                .sum();
+            if power > best_power {
+                (best, best_power) = (point, power);
+            }
+        }
+        let (azimuth, elevation) = self.grid[best];
+        DoaEstimate {
+            azimuth,
+            elevation,
+            strength: best_power * (n_upsampled / n_fft) as f32 / self.pairs.len() as f32,
+            level_db,
+        }
+    }
+}
+
+// Estimate over the mic samples the sender hands over with the header of
+// their packet, and send one JSON line per window, stamped with the first
+// packet of the window. Returns when the sender side is dropped.
+pub fn run_doa(
+    mut estimator: DoaEstimator,
+    packets: Receiver<(PacketHeader, Vec<Vec<f32>>)>,
+    lines: broadcast::Sender<Vec<u8>>,
+) {
+    let mut first: Option<PacketHeader> = None;
+    let mut next_sample = 0_u64;
+    while let Ok((header, mics)) = packets.recv() {
+        // a window only covers contiguous samples
+        if first.is_some() && header.sample_count != next_sample {
+            estimator.reset();
+            first = None;
+        }
+        next_sample = header.sample_count + mics.first().map_or(0, |mic| mic.len() as u64);
+        let first_header = *first.get_or_insert(header);
+        if let Some(estimate) = estimator.push(&mics) {
+            first = None;
+            let line = format!(
+                "{{\"device_id\":{},\"pkt_id\":{},\"sample_count\":{},\"capture_ns\":{},\"n_sample\":{},\"azimuth\":{:.1},\"elevation\":{:.1},\"strength\":{:.3},\"level_db\":{:.1}}}\n",
+                first_header.device_id,
+                first_header.pkt_id,
+                first_header.sample_count,
+                first_header.capture_ns,
+                estimator.window,
+                estimate.azimuth,
+                estimate.elevation,
+                estimate.strength,
+                estimate.level_db,
+            );
+            let _ = lines.send(line.into_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: usize = 16000;
+    const WINDOW: usize = 1024;
+
+    #[test]
+    fn finds_azimuth_of_plane_wave() {
+        // a square, so that no direction is mirrored by another
+        let array = BeamformerConfig {
+            mic_positions: vec![[0.05, 0.05, 0.0], [-0.05, 0.05, 0.0], [-0.05, -0.05, 0.0], [0.05, -0.05, 0.0]],
+            ..BeamformerConfig::default()
+        };
+        let cfg = DoaConfig::default();
+        for azimuth in [0.0_f32, 60.0, 135.0, 270.0] {
+            let mut estimator = DoaEstimator::new(&cfg, &array, 4, SAMPLE_RATE, WINDOW).unwrap();
+            let look = [azimuth.to_radians().cos(), azimuth.to_radians().sin()];
+            // broadband: a sum of sines, which can be delayed by any fraction of a sample
+            let tones: Vec<(f32, f32)> = (1..60).map(|k| (k as f32 * 113.0, k as f32 * 2.4)).collect();
+            let mics: Vec<Vec<f32>> = array
+                .mic_positions
+                .iter()
+                .map(|p| {
+                    let lead = (p[0] * look[0] + p[1] * look[1]) / array.speed_of_sound;
+                    (0..WINDOW)
+                        .map(|n| {
+                            let t = n as f32 / SAMPLE_RATE as f32 + lead;
+                            tones
+                                .iter()
+                                .map(|&(f, phase)| (2.0 * std::f32::consts::PI * f * t + phase).sin())
+                                .sum::<f32>()
+                                * 0.01
+                        })
+                        .collect()
+                })
+                .collect();
+            let estimate = estimator.push(&mics).unwrap();
+            let error = (estimate.azimuth - azimuth + 540.0).rem_euclid(360.0) - 180.0;
+            assert!(error.abs() <= cfg.azimuth_step, "{azimuth}: {estimate:?}");
+            assert!(estimate.strength > 0.5, "{azimuth}: {estimate:?}");
+        }
+    }
+
+    #[test]
+    fn needs_two_mics() {
+        let array = BeamformerConfig {
+            mic_positions: vec![[0.0, 0.0, 0.0], [0.1, 0.0, 0.0]],
+            ..BeamformerConfig::default()
+        };
+        assert!(DoaEstimator::new(&DoaConfig::default(), &array, 1, SAMPLE_RATE, WINDOW).is_none());
+        assert!(DoaEstimator::new(&DoaConfig::default(), &array, 2, SAMPLE_RATE, WINDOW).is_some());
+    }
+}
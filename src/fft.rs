@@ -0,0 +1,138 @@
+use std::f32::consts::PI;
+use std::ops::{Add, AddAssign, Mul, Sub};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Complex {
+    pub re: f32,
+    pub im: f32,
+}
+
+impl Complex {
+    pub const ZERO: Complex = Complex { re: 0.0, im: 0.0 };
+
+    pub fn new(re: f32, im: f32) -> Complex {
+        Complex { re, im }
+    }
+
+    pub fn conj(self) -> Complex {
+        Complex::new(self.re, -self.im)
+    }
+
+    pub fn norm_sqr(self) -> f32 {
+        self.re * self.re + self.im * self.im
+    }
+
+    pub fn scale(self, k: f32) -> Complex {
+        Complex::new(self.re * k, self.im * k)
+    }
+
+    pub fn recip(self) -> Complex {
+        self.conj().scale(1.0 / self.norm_sqr())
+    }
+
+    // e^(i phase)
+    pub fn from_phase(phase: f32) -> Complex {
+        Complex::new(phase.cos(), phase.sin())
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+    fn add(self, other: Complex) -> Complex {
+        Complex::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl AddAssign for Complex {
+    fn add_assign(&mut self, other: Complex) {
+        self.re += other.re;
+        self.im += other.im;
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+    fn sub(self, other: Complex) -> Complex {
+        Complex::new(self.re - other.re, self.im - other.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+    fn mul(self, other: Complex) -> Complex {
+        Complex::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+}
+
+// Radix-2 FFT of a fixed power of two size with precomputed twiddles.
+pub struct Fft {
+    n: usize,
+    twiddles: Vec<Complex>,
+    bit_reverse: Vec<usize>,
+}
+
+impl Fft {
+    pub fn new(n: usize) -> Fft {
+        assert!(n.is_power_of_two(), "FFT size must be a power of two");
+        let bits = n.trailing_zeros();
+        let bit_reverse = (0..n)
+            .map(|i| if bits == 0 { 0 } else { i.reverse_bits() >> (usize::BITS - bits) })
+            .collect();
+        let twiddles = (0..n / 2)
+            .map(|k| {
+                let phase = -2.0 * PI * k as f32 / n as f32;
+                Complex::new(phase.cos(), phase.sin())
+            })
+            .collect();
+        Fft { n, twiddles, bit_reverse }
+    }
+
+    pub fn forward(&self, data: &mut [Complex]) {
+        self.transform(data, false);
+    }
+
+    // Scaled by 1/n, so inverse(forward(x)) == x.
+    pub fn inverse(&self, data: &mut [Complex]) {
+        self.transform(data, true);
+        let k = 1.0 / self.n as f32;
+        for x in data.iter_mut() {
+            *x = x.scale(k);
+        }
+    }
+
+    // Spectrum of a real signal; 'input' shorter than the FFT is zero padded.
+    pub fn forward_real(&self, input: &[f32], out: &mut [Complex]) {
+        for (i, x) in out.iter_mut().enumerate() {
+            *x = Complex::new(input.get(i).copied().unwrap_or(0.0), 0.0);
+        }
+        self.forward(out);
+    }
+
+    fn transform(&self, data: &mut [Complex], inverse: bool) {
+        assert_eq!(data.len(), self.n);
+        for i in 0..self.n {
+            let j = self.bit_reverse[i];
+            if i < j {
+                data.swap(i, j);
+            }
+        }
+        let mut len = 2;
+        while len <= self.n {
+            let step = self.n / len;
+            for start in (0..self.n).step_by(len) {
+                for k in 0..len / 2 {
+                    let w = self.twiddles[k * step];
+                    let w = if inverse { w.conj() } else { w };
+                    let a = data[start + k];
+                    let b = data[start + k + len / 2] * w;
+                    data[start + k] = a + b;
+                    data[start + k + len / 2] = a - b;
+                }
+            }
+            len *= 2;
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use crate::config_file::Config;
+use std::path::Path;
+
+const PROC_ASOUND: &str = "/proc/asound";
+
+// ALSA card of a device name like "hw:ArrayUAC10", "plughw:CARD=Device,DEV=0"
+// or "hw:1,0"; either the card id or its index.
+pub fn alsa_card(device_name: &str) -> Option<String> {
+    let (_, spec) = device_name.split_once(':')?;
+    let card = spec.split(',').next()?;
+    let card = card.strip_prefix("CARD=").unwrap_or(card);
+    if card.is_empty() {
+        None
+    } else {
+        Some(card.to_string())
+    }
+}
+
+// Every card is listed in /proc/asound as cardN, plus a symlink named by its id.
+pub fn card_present(card: &str) -> bool {
+    let proc_asound = Path::new(PROC_ASOUND);
+    if card.chars().all(|c| c.is_ascii_digit()) {
+        proc_asound.join(format!("card{card}")).exists()
+    } else {
+        proc_asound.join(card).exists()
+    }
+}
+
+// The ALSA cards jackd was started on, so that unplugging one can be noticed
+// even when jackd keeps running without it.
+pub struct DeviceWatch {
+    cards: Vec<String>,
+}
+
+impl DeviceWatch {
+    // None if the devices cannot be watched: jackd is managed elsewhere, the
+    // driver is not ALSA or there is no /proc/asound.
+    pub fn new(cfg: &Config) -> Option<DeviceWatch> {
+        if !cfg.mic.watch_device
+            || !cfg.mic.start_jackd
+            || !cfg.mic.driver.to_lowercase().contains("alsa")
+            || !Path::new(PROC_ASOUND).exists()
+        {
+            return None;
+        }
+        let mut cards = Vec::new();
+        for device_name in [&cfg.mic.device_name, &cfg.speaker.device_name] {
+            match alsa_card(device_name) {
+                Some(card) if !cards.contains(&card) => cards.push(card),
+                Some(_) => {}
+                None => println!("cannot watch audio device {}", device_name),
+            }
+        }
+        Some(DeviceWatch { cards })
+    }
+
+    pub fn missing(&self) -> Option<&str> {
+        self.cards
+            .iter()
+            .find(|card| !card_present(card))
+            .map(|card| card.as_str())
+    }
+}
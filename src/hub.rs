@@ -0,0 +1,383 @@
+use crate::config_file::{Config, HubConfig};
+use crate::packet_header::PacketHeader;
+use crate::reconnect::{log_connection_events, ConnectionMonitor};
+use crate::tcp_client::TcpClient;
+use crate::tcp_server::start_server;
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinSet;
+use tokio::time::{self, Duration, Instant};
+
+// an upstream that sent nothing for this long is not waited for
+const UPSTREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+struct UpstreamTrack {
+    // body bytes of one packet (all channels, without header)
+    body_len: usize,
+    // merged slot = pkt_id + slot_offset while the upstream stays in sequence
+    slot_offset: Option<i64>,
+    last_pkt_id: i32,
+    last_seen: Option<Instant>,
+}
+
+struct PendingSlot {
+    // header and body of every upstream that sent its packet
+    bodies: Vec<Option<(PacketHeader, Vec<u8>)>>,
+    created: Instant,
+}
+
+// Places packets of several upstreams on a common timeline of packet slots
+// and emits one merged packet per slot with the channels concatenated.
+pub struct PacketAligner {
+    device_id: u16,
+    header_len: usize,
+    packet_millis: f64,
+    max_wait: Duration,
+    tracks: Vec<UpstreamTrack>,
+    origin_millis: Option<i64>,
+    // first slot not sent yet; until one is, an upstream whose clock is a
+    // little behind the first one to arrive may still fill earlier slots
+    next_slot: Option<i64>,
+    pending: BTreeMap<i64, PendingSlot>,
+    pkt_id: i32,
+    late_packets: u64,
+}
+
+impl PacketAligner {
+    pub fn new(cfg: &HubConfig) -> PacketAligner {
+        let tracks = cfg
+            .upstreams
+            .iter()
+            .map(|up| UpstreamTrack {
+                body_len: up.n_channel * cfg.sample_per_packet * cfg.sample_format.bytes(),
+                slot_offset: None,
+                last_pkt_id: 0,
+                last_seen: None,
+            })
+            .collect();
+        PacketAligner {
+            device_id: cfg.device_id as u16,
+            header_len: cfg.header_len,
+            packet_millis: cfg.sample_per_packet as f64 * 1000.0 / cfg.sample_rate as f64,
+            max_wait: Duration::from_millis(cfg.max_wait_ms),
+            tracks,
+            origin_millis: None,
+            next_slot: None,
+            pending: BTreeMap::new(),
+            pkt_id: 0,
+            late_packets: 0,
+        }
+    }
+
+    pub fn merged_pkt_len(&self) -> usize {
+        self.header_len + self.tracks.iter().map(|t| t.body_len).sum::<usize>()
+    }
+
+    pub fn push(&mut self, idx: usize, packet: &[u8], now: Instant) {
+        let header = PacketHeader::read_from(&packet[..self.header_len]);
+        let origin = *self.origin_millis.get_or_insert(header.unix_millis());
+        let by_time = ((header.unix_millis() - origin) as f64 / self.packet_millis).round() as i64;
+
+        let track = &mut self.tracks[idx];
+        // follow pkt_id while it is contiguous and agrees with the timestamp,
+        // otherwise (restart, dropped packets, clock step) re-lock on the timestamp
+        let slot = match track.slot_offset {
+            Some(offset)
+                if header.pkt_id == track.last_pkt_id.wrapping_add(1)
+                    && (header.pkt_id as i64 + offset - by_time).abs() <= 1 =>
+            {
+                header.pkt_id as i64 + offset
+            }
+            _ => {
+                track.slot_offset = Some(by_time - header.pkt_id as i64);
+                by_time
+            }
+        };
+        track.last_pkt_id = header.pkt_id;
+        track.last_seen = Some(now);
+
+        if self.next_slot.is_some_and(|next| slot < next) {
+            self.late_packets += 1;
+            if self.late_packets.is_power_of_two() {
+                println!("hub: {} late packets dropped", self.late_packets);
+            }
+            return;
+        }
+        let n_upstream = self.tracks.len();
+        let pending = self.pending.entry(slot).or_insert_with(|| PendingSlot {
+            bodies: vec![None; n_upstream],
+            created: now,
+        });
+        pending.bodies[idx] = Some((header, packet[self.header_len..].to_vec()));
+    }
+
+    // Pop merged packets whose slot is complete or has waited long enough.
+    pub fn pop_ready(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut merged = Vec::new();
+        while let Some(entry) = self.pending.first_entry() {
+            let slot = *entry.key();
+            let complete = entry.get().bodies.iter().zip(self.tracks.iter()).all(|(body, track)| {
+                body.is_some()
+                    || match track.last_seen {
+                        Some(seen) => now.duration_since(seen) > UPSTREAM_IDLE_TIMEOUT,
+                        None => true,
+                    }
+            });
+            if !complete && now.duration_since(entry.get().created) < self.max_wait {
+                break;
+            }
+            let pending = entry.remove();
+            merged.push(self.merge(slot, pending));
+            self.next_slot = Some(slot + 1);
+        }
+        merged
+    }
+
+    fn merge(&mut self, slot: i64, pending: PendingSlot) -> Vec<u8> {
+        let mut packet = vec![0_u8; self.merged_pkt_len()];
+        let slot_millis = self.origin_millis.unwrap_or(0)
+            + (slot as f64 * self.packet_millis).round() as i64;
+        let mut header = PacketHeader::from_unix_millis(self.device_id, slot_millis, self.pkt_id);
+        // sample counter and capture times of the first upstream in the slot,
+        // flags of all of them
+        let mut upstream_headers = pending.bodies.iter().flatten().map(|(header, _)| header);
+        if let Some(first) = upstream_headers.next() {
+            header.sample_count = first.sample_count;
+            header.capture_ns = first.capture_ns;
+            header.reference_ns = first.reference_ns;
+            header.flags = first.flags;
+        }
+        for upstream in upstream_headers {
+            header.flags |= upstream.flags;
+        }
+        header.write_to(&mut packet[..self.header_len]);
+
+        let mut s_idx = self.header_len;
+        for (body, track) in pending.bodies.iter().zip(self.tracks.iter()) {
+            let e_idx = s_idx + track.body_len;
+            if let Some((_, body)) = body {
+                packet[s_idx..e_idx].copy_from_slice(body);
+            }
+            s_idx = e_idx;
+        }
+
+        self.pkt_id += 1;
+        if self.pkt_id == i32::MAX {
+            self.pkt_id = 0;
+        }
+        packet
+    }
+}
+
+// Hub mode: connect to upstream mic2sock senders, re-serve each of them and
+// a merged stream of all of them.
+pub async fn run_hub(cfg: Arc<Config>) {
+    let hub = &cfg.hub;
+    let mut aligner = PacketAligner::new(hub);
+    println!(
+        "hub: merging {} upstreams into packets of {} bytes",
+        hub.upstreams.len(),
+        aligner.merged_pkt_len()
+    );
+
+    let mut tasks = JoinSet::new();
+    let (merge_tx, mut merge_rx) = mpsc::channel::<(usize, Vec<u8>)>(64);
+    for (idx, upstream) in hub.upstreams.iter().enumerate() {
+        let pkt_size = hub.header_len + upstream.n_channel * hub.sample_per_packet * hub.sample_format.bytes();
+        let monitor = Arc::new(ConnectionMonitor::new(&format!(
+            "hub upstream {}:{}",
+            upstream.host, upstream.port
+        )));
+        tokio::spawn(log_connection_events(
+            monitor.name().to_string(),
+            monitor.subscribe(),
+            monitor.metrics.clone(),
+        ));
+        let (resend, mut incoming) = mpsc::channel::<Vec<u8>>(16);
+        let mut client = TcpClient::new(
+            upstream.host.clone(),
+            upstream.port,
+            pkt_size,
+            resend,
+            &hub.reconnect,
+            monitor,
+        );
+        tasks.spawn(async move {
+            let _ = client.inf_run().await;
+        });
+
+        let packet_sender = (upstream.listen_port != 0).then(|| {
+            let (packet_sender, _) = broadcast::channel::<Vec<u8>>(16);
+            tasks.spawn(start_server(
+                upstream.listen_port,
+                hub.max_clients,
+                packet_sender.clone(),
+                tokio::signal::ctrl_c(),
+            ));
+            packet_sender
+        });
+        let merge_tx = merge_tx.clone();
+        tasks.spawn(async move {
+            while let Some(packet) = incoming.recv().await {
+                if let Some(packet_sender) = packet_sender.as_ref() {
+                    let _ = packet_sender.send(packet.clone());
+                }
+                if merge_tx.send((idx, packet)).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(merge_tx);
+
+    let (merged_sender, _) = broadcast::channel::<Vec<u8>>(16);
+    tasks.spawn(start_server(
+        hub.listen_port,
+        hub.max_clients,
+        merged_sender.clone(),
+        tokio::signal::ctrl_c(),
+    ));
+
+    let mut tick = time::interval(Duration::from_millis((hub.max_wait_ms / 4).max(1)));
+    tokio::select! {
+        _ = async {
+            loop {
+                tokio::select! {
+                    received = merge_rx.recv() => match received {
+                        Some((idx, packet)) => aligner.push(idx, &packet, Instant::now()),
+                        None => break,
+                    },
+                    _ = tick.tick() => {}
+                }
+                for packet in aligner.pop_ready(Instant::now()) {
+                    let _ = merged_sender.send(packet);
+                }
+            }
+        } => {}
+        _ = tokio::signal::ctrl_c() => {
+            println!("Stop hub");
+        }
+    }
+    tasks.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_file::HubUpstream;
+    use crate::packet_header::{FLAGS_HEADER_LEN, FLAG_DEVICE_DOWN};
+
+    const HEADER_LEN: usize = FLAGS_HEADER_LEN;
+
+    // two upstreams of one channel, 10 ms packets
+    fn aligner() -> PacketAligner {
+        let upstream = HubUpstream {
+            host: String::new(),
+            port: 0,
+            n_channel: 1,
+            listen_port: 0,
+        };
+        PacketAligner::new(&HubConfig {
+            header_len: HEADER_LEN,
+            upstreams: vec![upstream.clone(), upstream],
+            ..HubConfig::default()
+        })
+    }
+
+    fn packet(unix_millis: i64, pkt_id: i32, fill: u8) -> Vec<u8> {
+        flagged_packet(unix_millis, pkt_id, fill, 0)
+    }
+
+    fn flagged_packet(unix_millis: i64, pkt_id: i32, fill: u8, flags: u16) -> Vec<u8> {
+        let mut packet = vec![fill; HEADER_LEN + 320];
+        let mut header = PacketHeader::from_unix_millis(1, unix_millis, pkt_id);
+        header.sample_count = pkt_id as u64 * 160;
+        header.capture_ns = unix_millis * 1_000_000;
+        header.flags = flags;
+        header.write_to(&mut packet[..HEADER_LEN]);
+        packet
+    }
+
+    fn header(packet: &[u8]) -> PacketHeader {
+        PacketHeader::read_from(&packet[..HEADER_LEN])
+    }
+
+    fn bodies(packet: &[u8]) -> (u8, u8) {
+        (packet[HEADER_LEN], packet[HEADER_LEN + 320])
+    }
+
+    #[test]
+    fn merges_out_of_order_packets() {
+        let mut aligner = aligner();
+        let now = Instant::now();
+        aligner.push(0, &packet(1010, 1, 2), now);
+        // the packet above set the origin, this slot before it is not late
+        aligner.push(0, &packet(1000, 0, 1), now);
+        aligner.push(1, &packet(1000, 5, 3), now);
+        aligner.push(1, &packet(1010, 6, 4), now);
+
+        let merged = aligner.pop_ready(now);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(bodies(&merged[0]), (1, 3));
+        assert_eq!(bodies(&merged[1]), (2, 4));
+        let (first, second) = (header(&merged[0]), header(&merged[1]));
+        assert_eq!((first.pkt_id, second.pkt_id), (0, 1));
+        // sample counter of the first upstream
+        assert_eq!((first.sample_count, second.sample_count), (0, 160));
+        assert_eq!(second.capture_ns - first.capture_ns, 10_000_000);
+        assert_eq!(second.unix_millis() - first.unix_millis(), 10);
+        assert_eq!(aligner.late_packets, 0);
+    }
+
+    #[test]
+    fn merges_flags_of_all_upstreams() {
+        let mut aligner = aligner();
+        let now = Instant::now();
+        aligner.push(0, &packet(1000, 0, 1), now);
+        aligner.push(1, &flagged_packet(1000, 0, 0, FLAG_DEVICE_DOWN), now);
+        let merged = aligner.pop_ready(now);
+        assert_eq!(header(&merged[0]).flags, FLAG_DEVICE_DOWN);
+    }
+
+    #[test]
+    fn drops_late_packets() {
+        let mut aligner = aligner();
+        let now = Instant::now();
+        aligner.push(0, &packet(1000, 0, 1), now);
+        aligner.push(1, &packet(1000, 0, 2), now);
+        assert_eq!(aligner.pop_ready(now).len(), 1);
+
+        aligner.push(0, &packet(1010, 1, 1), now);
+        assert!(aligner.pop_ready(now).is_empty());
+        let later = now + aligner.max_wait;
+        let merged = aligner.pop_ready(later);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(bodies(&merged[0]), (1, 0));
+
+        aligner.push(1, &packet(1010, 1, 2), later);
+        assert_eq!(aligner.late_packets, 1);
+        assert!(aligner.pop_ready(later + aligner.max_wait).is_empty());
+    }
+
+    #[test]
+    fn does_not_wait_for_idle_upstream() {
+        let mut aligner = aligner();
+        let now = Instant::now();
+        // an upstream never heard from does not hold slots back
+        aligner.push(0, &packet(1000, 0, 1), now);
+        assert_eq!(aligner.pop_ready(now).len(), 1);
+
+        aligner.push(1, &packet(1010, 1, 2), now);
+        aligner.push(0, &packet(1010, 1, 1), now);
+        assert_eq!(aligner.pop_ready(now).len(), 1);
+
+        // nor does one that went silent
+        let later = now + UPSTREAM_IDLE_TIMEOUT + Duration::from_millis(10);
+        aligner.push(0, &packet(1020, 2, 1), later);
+        let merged = aligner.pop_ready(later);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(bodies(&merged[0]), (1, 0));
+    }
+}
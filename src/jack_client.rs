@@ -1,12 +1,34 @@
-use crate::config_file::Config;
-use std::{sync::Arc, io::Write};
+use crate::config_file::{ChannelMapping, Config, RouteSource};
+use crate::routing::RoutingMatrix;
+use arc_swap::ArcSwap;
+use crate::audio_clock::{AudioClock, CaptureGaps, ClockReference};
+use crate::hotplug::DeviceWatch;
+use crate::system_call::{wait_for_jackd, CaptureBridges, JackServer};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use jack::{RingBufferWriter, RingBufferReader};
+use tokio::runtime::Handle;
 use tokio::sync::Notify;
-use crossbeam::channel::Receiver;
+use crossbeam::channel::{Receiver, RecvTimeoutError};
 use std::sync::atomic::{AtomicBool, Ordering};
 
+// wait between attempts to bring the audio session back
+const RESTART_INTERVAL: Duration = Duration::from_secs(1);
+const CLIENT_NAME: &str = "rust_client";
+// type of the ports jack::AudioIn and jack::AudioOut register
+const AUDIO_PORT_TYPE: &str = "32 bit float mono audio";
+// how often the watched audio devices are checked
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const JACKD_RESTART_TIMEOUT: Duration = Duration::from_secs(10);
+// how long a killed jackd may take to exit
+const JACKD_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
 struct Notifications {
-    panic_trigger: Arc<AtomicBool>,
+    // set when the server shuts the client down
+    failed: Arc<AtomicBool>,
+    // set when ports come or go; the supervisor connects new ones, as
+    // connecting from the notification thread deadlocks
+    ports_changed: Arc<AtomicBool>,
 }
 
 impl jack::NotificationHandler for Notifications {
@@ -16,7 +38,7 @@ impl jack::NotificationHandler for Notifications {
 
     fn shutdown(&mut self, status: jack::ClientStatus, reason: &str) {
         eprintln!("JACK FATAL: {:?} - {}", status, reason);
-        self.panic_trigger.store(true, Ordering::SeqCst);
+        self.failed.store(true, Ordering::SeqCst);
     }
 
     fn freewheel(&mut self, _: &jack::Client, is_enabled: bool) {
@@ -45,6 +67,7 @@ impl jack::NotificationHandler for Notifications {
             if is_reg { "registered" } else { "unregistered" },
             port_id
         );
+        self.ports_changed.store(true, Ordering::Release);
     }
 
     fn port_rename(
@@ -91,56 +114,568 @@ impl jack::NotificationHandler for Notifications {
     }
 }
 
-pub fn inspect_device() -> (jack::Client, usize, usize) {
-    let (client, _status) =
-        jack::Client::new("rust_client", jack::ClientOptions::NO_START_SERVER).unwrap();
+// Number of capture and playback channels the main device offers: the
+// physical ports, or the mapped channels when the config lists them.
+pub fn inspect_device(client: &jack::Client, cfg: &Config) -> (usize, usize) {
+    let in_ports_name = main_capture_ports(client, cfg);
+    let out_ports_name = playback_ports(client, cfg);
+    println!("capture ports: {:?}", in_ports_name);
+    println!("playback ports: {:?}", out_ports_name);
+    (in_ports_name.len(), out_ports_name.len())
+}
 
-    let in_ports_name = client.ports(Some("capture"), None, jack::PortFlags::IS_PHYSICAL);
-    let out_ports_name = client.ports(Some("playback"), None, jack::PortFlags::IS_INPUT);
-    println!("physical input: {:?}", in_ports_name);
-    println!("physical output: {:?}", out_ports_name);
-    (client, in_ports_name.len(), out_ports_name.len())
+// Capture port of every stream channel, in stream order.
+pub fn capture_ports(client: &jack::Client, cfg: &Config) -> Vec<Option<String>> {
+    let mut ports = main_capture_ports(client, cfg);
+    ports.resize(cfg.mic.n_channel, None);
+    for device in cfg.mic.extra_devices.iter() {
+        let mut device_ports: Vec<Option<String>> = other_client_ports(
+            client,
+            &format!("^{}:", device.jack_name()),
+            jack::PortFlags::IS_OUTPUT,
+        )
+        .into_iter()
+        .map(Some)
+        .collect();
+        // the bridge may not be running yet
+        device_ports.resize(device.n_channel, None);
+        ports.extend(device_ports);
+    }
+    ports
 }
 
-pub fn start_jack_client(
-    cfg: Arc<Config>,
-    client: jack::Client,
+fn main_capture_ports(client: &jack::Client, cfg: &Config) -> Vec<Option<String>> {
+    if !cfg.mic.channels.is_empty() {
+        return cfg
+            .mic
+            .channels
+            .iter()
+            .map(|mapping| resolve_port(client, mapping, jack::PortFlags::IS_OUTPUT))
+            .collect();
+    }
+    if !cfg.mic.capture_pattern.is_empty() {
+        let mut ports: Vec<Option<String>> = other_client_ports(
+            client,
+            &cfg.mic.capture_pattern,
+            jack::PortFlags::IS_OUTPUT,
+        )
+        .into_iter()
+        .map(Some)
+        .collect();
+        // the ports may show up later
+        ports.resize(cfg.mic.n_channel, None);
+        return ports;
+    }
+    client
+        .ports(Some("capture"), None, jack::PortFlags::IS_PHYSICAL)
+        .into_iter()
+        .map(Some)
+        .collect()
+}
+
+// Playback port of every received channel, in stream order.
+pub fn playback_ports(client: &jack::Client, cfg: &Config) -> Vec<Option<String>> {
+    if cfg.speaker.channels.is_empty() {
+        return client
+            .ports(Some("playback"), None, jack::PortFlags::IS_INPUT)
+            .into_iter()
+            .map(Some)
+            .collect();
+    }
+    cfg.speaker
+        .channels
+        .iter()
+        .map(|mapping| resolve_port(client, mapping, jack::PortFlags::IS_INPUT))
+        .collect()
+}
+
+fn resolve_port(
+    client: &jack::Client,
+    mapping: &ChannelMapping,
+    flags: jack::PortFlags,
+) -> Option<String> {
+    if let Some(port) = &mapping.port {
+        client.port_by_name(port).map(|_| port.clone())
+    } else if let Some(pattern) = &mapping.pattern {
+        other_client_ports(client, pattern, flags).into_iter().next()
+    } else if let Some(index) = mapping.index {
+        client
+            .ports(None, Some(AUDIO_PORT_TYPE), flags | jack::PortFlags::IS_PHYSICAL)
+            .into_iter()
+            .nth(index)
+    } else {
+        None
+    }
+}
+
+// Audio ports matching 'pattern', except our own.
+fn other_client_ports(client: &jack::Client, pattern: &str, flags: jack::PortFlags) -> Vec<String> {
+    let own_prefix = format!("{}:", CLIENT_NAME);
+    client
+        .ports(Some(pattern), Some(AUDIO_PORT_TYPE), flags)
+        .into_iter()
+        .filter(|name| !name.starts_with(&own_prefix))
+        .collect()
+}
+
+// Connect every stream channel to its port unless it already is, and drop
+// links to ports that no longer map to the channel (e.g. a pattern that now
+// matches another port), so no channel sums several sources; ports that do
+// not exist (yet) are reported when 'report_missing' is set.
+fn connect_ports(client: &jack::Client, cfg: &Config, report_missing: bool) {
+    let in_ports_name = capture_ports(client, cfg);
+    let out_ports_name = playback_ports(client, cfg);
+    let links = in_ports_name
+        .iter()
+        .take(cfg.mic.total_channels())
+        .enumerate()
+        .map(|(i, port_name)| (port_name, format!("{CLIENT_NAME}:in_{i}"), true))
+        .chain(
+            out_ports_name
+                .iter()
+                .take(cfg.speaker.n_channel)
+                .enumerate()
+                .map(|(i, port_name)| (port_name, format!("{CLIENT_NAME}:out_{i}"), false)),
+        );
+    // every port a channel can be linked with, outputs for the capture side
+    let sources = client.ports(None, Some(AUDIO_PORT_TYPE), jack::PortFlags::IS_OUTPUT);
+    let sinks = client.ports(None, Some(AUDIO_PORT_TYPE), jack::PortFlags::IS_INPUT);
+    for (port_name, own_port, is_capture) in links {
+        let mut connected = false;
+        if let Some(port) = client.port_by_name(&own_port) {
+            let candidates = if is_capture { &sources } else { &sinks };
+            for linked in candidates.iter().filter(|name| port.is_connected_to(name).unwrap_or(false)) {
+                if Some(linked) == port_name.as_ref() {
+                    connected = true;
+                    continue;
+                }
+                let res = if is_capture {
+                    client.disconnect_ports_by_name(linked, &own_port)
+                } else {
+                    client.disconnect_ports_by_name(&own_port, linked)
+                };
+                match res {
+                    Ok(()) => println!("JACK: disconnected {} from {}", linked, own_port),
+                    Err(err) => println!("JACK: failed to disconnect {}. {}", linked, err),
+                }
+            }
+        }
+        let Some(port_name) = port_name else {
+            if report_missing {
+                println!("JACK: no port for {} yet", own_port);
+            }
+            continue;
+        };
+        if connected {
+            continue;
+        }
+        let res = if is_capture {
+            client.connect_ports_by_name(port_name, &own_port)
+        } else {
+            client.connect_ports_by_name(&own_port, port_name)
+        };
+        match res {
+            Ok(()) if !report_missing => println!("JACK: connected {} to {}", port_name, own_port),
+            Ok(()) => {}
+            Err(err) => println!("JACK: failed to connect {}. {}", port_name, err),
+        }
+    }
+}
+
+// Audio state that outlives a JACK client, so a client opened after the
+// server restarted continues the same ring buffers and sample counters.
+pub struct CaptureState {
+    // f32 samples of every mic channel
+    buf_writers: Vec<RingBufferWriter>,
+    // f32 samples of the received channels as played, in step with the
+    // capture rings; only used for echo cancellation
+    echo_ref_writers: Vec<RingBufferWriter>,
+    playback_buf_readers: Vec<RingBufferReader>,
+    // samples captured since the last notification
+    i_sample: usize,
+    captured_samples: u64,
+    ring_samples: u64,
+}
+
+impl CaptureState {
+    pub fn new(
+        buf_writers: Vec<RingBufferWriter>,
+        echo_ref_writers: Vec<RingBufferWriter>,
+        playback_buf_readers: Vec<RingBufferReader>,
+    ) -> CaptureState {
+        CaptureState {
+            buf_writers,
+            echo_ref_writers,
+            playback_buf_readers,
+            i_sample: 0,
+            captured_samples: 0,
+            ring_samples: 0,
+        }
+    }
+}
+
+// Everything an audio session shares with the rest of the program.
+pub struct AudioContext {
+    pub cfg: Arc<Config>,
+    pub notifier: Arc<Notify>,
+    pub state: Arc<Mutex<CaptureState>>,
+    pub audio_clock: Arc<AudioClock>,
+    pub capture_gaps: Arc<CaptureGaps>,
+    // set while no JACK client is running
+    pub device_down: Arc<AtomicBool>,
+    pub routing: Arc<ArcSwap<RoutingMatrix>>,
+}
+
+// Real-time part of the client; JACK calls 'process' once per period.
+struct Processor {
+    in_ports: Vec<jack::Port<jack::AudioIn>>,
+    out_ports: Vec<jack::Port<jack::AudioOut>>,
+    state: Arc<Mutex<CaptureState>>,
     notifier: Arc<Notify>,
-    mut buf_writers: Vec<RingBufferWriter>,
-    mut playback_buf_readers: Vec<RingBufferReader>,
-    shutdown: Receiver<()>,
-    panic_trigger: Arc<AtomicBool>,
-) {
-    let mut i16_buf = vec![0_i16; cfg.mic.period];
-    let period = cfg.mic.period;
-    let sample_per_packet = cfg.tcp_sender.sample_per_packet;
-    let mut i_sample : usize = 0;
+    audio_clock: Arc<AudioClock>,
+    capture_gaps: Arc<CaptureGaps>,
+    sample_rate: i64,
+    sample_per_packet: usize,
+    // one period of every received channel, mixed to the outputs by 'routing'
+    recv_buf: Vec<Vec<f32>>,
+    routing: Arc<ArcSwap<RoutingMatrix>>,
+    first_cycle: bool,
+    // periods dropped while 'state' was locked, not recorded as a gap yet
+    missed_frames: u64,
+    fade: PlaybackFade,
+    // gain of every sample of the current period, shared by the received channels
+    fade_buf: Vec<f32>,
+}
 
-    let in_ports_name = client.ports(Some("capture"), None, jack::PortFlags::IS_PHYSICAL);
-    let out_ports_name = client.ports(Some("playback"), None, jack::PortFlags::IS_INPUT);
+// Gain of the received channels. Playback starts with a fade in once the
+// ring holds a period plus 'reserve' samples; when it holds less, what is
+// left is played fading out, so the ring never runs dry at full level.
+// The gain ramps from wherever it is, so a stream coming back during a fade
+// out turns into a fade in without a step.
+struct PlaybackFade {
+    gain: f32,
+    step: f32,
+    reserve: usize,
+}
 
-    let mut in_ports = Vec::<jack::Port<jack::AudioIn>>::new();
-    for i in 0..cfg.mic.n_channel {
-        in_ports.push(
-            client
-                .register_port(format!("in_{i}").as_str(), jack::AudioIn::default())
-                .unwrap()
+impl PlaybackFade {
+    fn new(fade_ms: usize, sample_rate: usize) -> PlaybackFade {
+        let len = fade_ms * sample_rate / 1000;
+        PlaybackFade {
+            gain: 0.0,
+            step: 1.0 / len.max(1) as f32,
+            reserve: len,
+        }
+    }
+
+    // Samples to read from rings holding 'level' samples for a period of
+    // 'n_frames', with their gains written to 'gains'.
+    fn next(&mut self, level: usize, n_frames: usize, gains: &mut [f32]) -> usize {
+        if level >= n_frames + self.reserve {
+            for gain in gains[..n_frames].iter_mut() {
+                self.gain = (self.gain + self.step).min(1.0);
+                *gain = self.gain;
+            }
+            return n_frames;
+        }
+        // steeper than usual when less is left than the fade needs; what is
+        // left once the gain is down stays for the next fade in
+        let step = self.step.max(self.gain / level.max(1) as f32);
+        let n_read = level.min(n_frames).min((self.gain / step).ceil() as usize);
+        for gain in gains[..n_read].iter_mut() {
+            self.gain = (self.gain - step).max(0.0);
+            *gain = self.gain;
+        }
+        if n_read == level {
+            self.gain = 0.0;
+        }
+        n_read
+    }
+}
+
+impl jack::ProcessHandler for Processor {
+    fn process(&mut self, _: &jack::Client, ps: &jack::ProcessScope) -> jack::Control {
+        let n_frames = ps.n_frames() as usize;
+        // only one client runs at a time, so the lock is hardly ever contended
+        let mut state = match self.state.try_lock() {
+            Ok(state) => state,
+            Err(_) => {
+                // the ring position is behind the lock; count the period as
+                // skipped once it is free again
+                self.missed_frames += n_frames as u64;
+                for port in self.out_ports.iter_mut() {
+                    port.as_mut_slice(ps).fill(0.0);
+                }
+                return jack::Control::Continue;
+            }
+        };
+        let state = &mut *state;
+        if self.missed_frames > 0 {
+            self.capture_gaps.record_skip(state.ring_samples, self.missed_frames);
+            state.captured_samples += self.missed_frames;
+            self.missed_frames = 0;
+        }
+        // buffer_size() is always called first; never allocate here, drop
+        // the period instead so later packets keep their capture time
+        if n_frames > self.fade_buf.len() {
+            self.capture_gaps.record_skip(state.ring_samples, n_frames as u64);
+            state.captured_samples += n_frames as u64;
+            for port in self.out_ports.iter_mut() {
+                port.as_mut_slice(ps).fill(0.0);
+            }
+            return jack::Control::Continue;
+        }
+
+        if let Some(port) = self.in_ports.first() {
+            let jack_micros = capture_time_micros(ps, port, self.sample_rate);
+            if self.first_cycle {
+                self.first_cycle = false;
+                // after a restart, count the time the device was down as skipped
+                // samples so sample counters keep following real time
+                let last = self.audio_clock.load();
+                if last.jack_micros != 0 {
+                    let expected_micros = last.jack_micros_at(state.captured_samples, self.sample_rate as usize);
+                    let skipped = (jack_micros - expected_micros) * self.sample_rate / 1_000_000;
+                    if skipped > 0 {
+                        self.capture_gaps.record_skip(state.ring_samples, skipped as u64);
+                        state.captured_samples += skipped as u64;
+                    }
+                }
+            }
+            self.audio_clock.publish(ClockReference {
+                sample: state.captured_samples,
+                jack_micros,
+            });
+        }
+        // drop the whole cycle on every channel if any ring is full, so the
+        // channels stay aligned; the sender learns about it from 'capture_gaps'
+        let captured = state
+            .buf_writers
+            .iter_mut()
+            .chain(state.echo_ref_writers.iter_mut())
+            .all(|writer| writer.space() >= n_frames * 4);
+        if captured {
+            for (port, writer) in self.in_ports.iter().zip(state.buf_writers.iter_mut()) {
+                writer.write_buffer(slice_f32_to_u8(port.as_slice(ps)));
+            }
+            state.ring_samples += n_frames as u64;
+        } else {
+            self.capture_gaps.record_overrun(state.ring_samples, n_frames as u64);
+        }
+        state.captured_samples += n_frames as u64;
+
+        // packets need not be a multiple of the period; the sender drains
+        // every complete packet, so one notification per crossing is enough
+        state.i_sample += n_frames;
+        if state.i_sample >= self.sample_per_packet {
+            self.notifier.notify_one();
+            state.i_sample %= self.sample_per_packet;
+        }
+
+        // the receiver writes every channel at once, the first ring stands for all
+        let level = state.playback_buf_readers.first().map_or(0, |reader| reader.space() / 4);
+        let n_read = self.fade.next(level, n_frames, &mut self.fade_buf);
+        for (recv, reader) in self.recv_buf.iter_mut().zip(state.playback_buf_readers.iter_mut()) {
+            let recv = &mut recv[..n_frames];
+            let _n_bytes = reader.read_buffer(slice_f32_to_u8_mut(&mut recv[..n_read]));
+            for (r, &gain) in recv.iter_mut().zip(self.fade_buf[..n_read].iter()) {
+                *r *= gain;
+            }
+            recv[n_read..].fill(0.0);
+        }
+        // the echo canceller gets the received channels as played, before
+        // the routing mixes in anything it should not cancel
+        if captured {
+            for (recv, writer) in self.recv_buf.iter().zip(state.echo_ref_writers.iter_mut()) {
+                writer.write_buffer(slice_f32_to_u8(&recv[..n_frames]));
+            }
+        }
+
+        for port in self.out_ports.iter_mut() {
+            port.as_mut_slice(ps).fill(0.0);
+        }
+        // the matrix checked every index when it was built
+        let routing = self.routing.load();
+        for route in routing.routes() {
+            let source = match route.source {
+                RouteSource::Mic => self.in_ports[route.channel].as_slice(ps),
+                RouteSource::Recv => &self.recv_buf[route.channel][..n_frames],
+            };
+            let out_data_mut = self.out_ports[route.output].as_mut_slice(ps);
+            for (out, &s) in out_data_mut.iter_mut().zip(source.iter()) {
+                *out += s * route.gain;
+            }
+        }
+
+        jack::Control::Continue
+    }
+
+    fn buffer_size(&mut self, _: &jack::Client, size: jack::Frames) -> jack::Control {
+        println!("JACK: buffer size is {}", size);
+        // called outside of the process cycle, so allocating is fine
+        if self.fade_buf.len() < size as usize {
+            self.fade_buf.resize(size as usize, 0.0);
+            for recv in self.recv_buf.iter_mut() {
+                recv.resize(size as usize, 0.0);
+            }
+        }
+        jack::Control::Continue
+    }
+}
+
+pub fn open_client() -> Result<jack::Client, jack::Error> {
+    let (client, _status) =
+        jack::Client::new(CLIENT_NAME, jack::ClientOptions::NO_START_SERVER)?;
+    Ok(client)
+}
+
+// Register ports, activate the client and connect it to the hardware.
+fn start_session(
+    ctx: &AudioContext,
+    client: jack::Client,
+    failed: Arc<AtomicBool>,
+    ports_changed: Arc<AtomicBool>,
+) -> Result<jack::AsyncClient<Notifications, Processor>, jack::Error> {
+    let cfg = &ctx.cfg;
+    if client.buffer_size() as usize != cfg.mic.period {
+        println!(
+            "JACK runs with buffer size {} instead of mic.period {}",
+            client.buffer_size(),
+            cfg.mic.period
         );
     }
+
+    let mut in_ports = Vec::<jack::Port<jack::AudioIn>>::new();
+    for i in 0..cfg.mic.total_channels() {
+        in_ports.push(client.register_port(format!("in_{i}").as_str(), jack::AudioIn)?);
+    }
     let mut out_ports = Vec::<jack::Port<jack::AudioOut>>::new();
     for i in 0..cfg.speaker.n_channel {
-        out_ports.push(
-            client
-                .register_port(format!("out_{i}").as_str(), jack::AudioOut::default())
-                .unwrap()
-        );
-        
+        out_ports.push(client.register_port(format!("out_{i}").as_str(), jack::AudioOut)?);
     }
 
     let notifications = Notifications {
-        panic_trigger: panic_trigger.clone(),
+        failed,
+        ports_changed,
+    };
+    let buffer_size = client.buffer_size().max(cfg.mic.period as u32) as usize;
+
+    let process = Processor {
+        in_ports,
+        out_ports,
+        state: ctx.state.clone(),
+        notifier: ctx.notifier.clone(),
+        audio_clock: ctx.audio_clock.clone(),
+        capture_gaps: ctx.capture_gaps.clone(),
+        sample_rate: cfg.mic.sample_rate as i64,
+        // a packet in device samples
+        sample_per_packet: (cfg.tcp_sender.sample_per_packet * cfg.mic.sample_rate / cfg.mic.stream_rate()).max(1),
+        recv_buf: vec![vec![0.0; buffer_size]; cfg.tcp_receiver.n_channel],
+        routing: ctx.routing.clone(),
+        first_cycle: true,
+        missed_frames: 0,
+        fade: PlaybackFade::new(cfg.speaker.fade_ms, cfg.mic.sample_rate),
+        fade_buf: vec![0.0; buffer_size],
+    };
+    let active_client = client.activate_async(notifications, process)?;
+
+    connect_ports(active_client.as_client(), cfg, true);
+    Ok(active_client)
+}
+
+// Returns true once 'shutdown' fires (or its sender is gone) within 'timeout'.
+fn wait_for_shutdown(shutdown: &Receiver<()>, timeout: Duration) -> bool {
+    !matches!(shutdown.recv_timeout(timeout), Err(RecvTimeoutError::Timeout))
+}
+
+// Why an audio session ended.
+enum SessionEnd {
+    Shutdown,
+    // the server shut the client down
+    Failed,
+    // a watched ALSA card disappeared
+    DeviceLost,
+}
+
+// Run the session until shutdown, a server failure or until 'check_devices'
+// (called every DEVICE_POLL_INTERVAL) reports a lost device, and connect
+// ports of other clients as they appear.
+fn watch_session(
+    client: &jack::Client,
+    cfg: &Config,
+    failed: &AtomicBool,
+    ports_changed: &AtomicBool,
+    shutdown: &Receiver<()>,
+    mut check_devices: impl FnMut() -> bool,
+) -> SessionEnd {
+    let mut last_device_check = Instant::now();
+    loop {
+        if wait_for_shutdown(shutdown, Duration::from_millis(100)) {
+            return SessionEnd::Shutdown;
+        }
+        if failed.load(Ordering::Acquire) {
+            return SessionEnd::Failed;
+        }
+        if ports_changed.swap(false, Ordering::AcqRel) {
+            connect_ports(client, cfg, false);
+        }
+        if last_device_check.elapsed() >= DEVICE_POLL_INTERVAL {
+            last_device_check = Instant::now();
+            if !check_devices() {
+                return SessionEnd::DeviceLost;
+            }
+        }
+    }
+}
+
+// Start jackd again once all watched devices are present and wait until it
+// accepts clients. Returns false on shutdown.
+fn restart_jackd(
+    cfg: &Arc<Config>,
+    jack_server: &mut JackServer,
+    device_watch: Option<&DeviceWatch>,
+    runtime: &Handle,
+    shutdown: &Receiver<()>,
+) -> bool {
+    runtime.block_on(jack_server.stop(JACKD_STOP_TIMEOUT));
+    let mut reported_missing = false;
+    while let Some(card) = device_watch.and_then(|watch| watch.missing()) {
+        if !reported_missing {
+            reported_missing = true;
+            println!("waiting for audio device {}", card);
+        }
+        if wait_for_shutdown(shutdown, DEVICE_POLL_INTERVAL) {
+            return false;
+        }
+    }
+    let started = {
+        let _runtime = runtime.enter();
+        JackServer::start(cfg.clone())
     };
-    
+    match started {
+        Ok(server) => *jack_server = server,
+        Err(err) => {
+            println!("failed to start jackd! {}", err);
+            return true;
+        }
+    }
+    if let Err(err) = runtime.block_on(wait_for_jackd(jack_server, JACKD_RESTART_TIMEOUT)) {
+        println!("{}", err);
+    }
+    true
+}
+
+// Keep an audio session running until 'shutdown'. When the JACK server goes
+// away or a watched USB device is unplugged, the device is flagged as down,
+// jackd is restarted (once the device is back) and a new client with the
+// same channel layout is opened.
+pub fn run_audio(
+    ctx: AudioContext,
+    client: jack::Client,
+    mut jack_server: JackServer,
+    runtime: Handle,
+    shutdown: Receiver<()>,
+) {
     struct CleanupGuard;
     impl Drop for CleanupGuard {
         fn drop(&mut self) {
@@ -150,107 +685,162 @@ pub fn start_jack_client(
     }
     let _guard = CleanupGuard;
 
-    // jack client will call this function each period
-    let mut _fade_in = 0.01;
-    let process_callback = move |_: &jack::Client, ps: &jack::ProcessScope| -> jack::Control {
-        for (i, port) in in_ports.iter().enumerate() {
-            let in_data = port.as_slice(ps);
-            assert_eq!(in_data.len(), period);
-            for j in 0..period {
-                i16_buf[j] = pcm_f32_to_i16(in_data[j]);
+    let device_watch = DeviceWatch::new(&ctx.cfg);
+    let mut bridges = CaptureBridges::new(&ctx.cfg);
+    let mut client = Some(client);
+    loop {
+        let session_client = match client.take().map_or_else(open_client, Ok) {
+            Ok(session_client) => session_client,
+            Err(err) => {
+                println!("JACK: server not available. {}", err);
+                if wait_for_shutdown(&shutdown, RESTART_INTERVAL) {
+                    return;
+                }
+                continue;
             }
-            buf_writers[i].write_all(slice_i16_to_u8(i16_buf.as_slice())).unwrap();
-        }
-        i_sample += period;
-        if i_sample >= sample_per_packet {
-            notifier.notify_one();
-            i_sample -= sample_per_packet;
-        }
-
-        let mut playback_data_available = true;
-        if out_ports.len() == 0 || playback_buf_readers[0].space() < period * 2 { 
-            playback_data_available = false;
-        }
-        for i in 0..out_ports.len() {
-            let out_data_mut= out_ports[i].as_mut_slice(ps);
-            if playback_data_available {
-                let _n_bytes = playback_buf_readers[i].read_buffer(slice_i16_to_u8_mut(i16_buf.as_mut_slice())); 
-                assert_eq!(_n_bytes, period *2);
+        };
+        let failed = Arc::new(AtomicBool::new(false));
+        let ports_changed = Arc::new(AtomicBool::new(false));
+        let active_client = match start_session(
+            &ctx,
+            session_client,
+            failed.clone(),
+            ports_changed.clone(),
+        ) {
+            Ok(active_client) => active_client,
+            Err(err) => {
+                println!("JACK: failed to start audio session. {}", err);
+                if wait_for_shutdown(&shutdown, RESTART_INTERVAL) {
+                    return;
+                }
+                continue;
             }
-            for j in 0..out_data_mut.len() {
-                if playback_data_available {
-                    out_data_mut[j] = pcm_i16_to_f32(i16_buf[j]);// * fade_in;
-                } else {
-                    out_data_mut[j] = 0.0;
+        };
+        ctx.device_down.store(false, Ordering::Release);
+
+        // extra capture devices need a running server; their ports are
+        // connected when they register
+        let mut check_devices = || {
+            let _runtime = runtime.enter();
+            bridges.ensure_running(&ctx.cfg);
+            match device_watch.as_ref().and_then(|watch| watch.missing()) {
+                Some(card) => {
+                    println!("audio device {} disappeared", card);
+                    false
                 }
+                None => true,
             }
+        };
+        check_devices();
+        let end = watch_session(
+            active_client.as_client(),
+            &ctx.cfg,
+            &failed,
+            &ports_changed,
+            &shutdown,
+            check_devices,
+        );
+        if let SessionEnd::Shutdown = end {
+            println!("shutting down jack client");
+            let _ = active_client.deactivate();
+            return;
         }
-        if _fade_in < 1.0 {
-            _fade_in += 0.01;
-        }
-
-        jack::Control::Continue
-    };
 
-    let process = jack::ClosureProcessHandler::new(process_callback);
-    let active_client = 
-        client.activate_async(notifications, process).unwrap();
+        ctx.device_down.store(true, Ordering::Release);
+        println!("JACK: audio device down, trying to restart");
+        // deactivating fails if the server is gone; the handlers are leaked
+        // then, but the capture state lives on in 'ctx'
+        drop(active_client);
 
-    for i in 0..cfg.mic.n_channel {
-        active_client
-            .as_client()
-            .connect_ports_by_name(&in_ports_name[i], format!("rust_client:in_{i}").as_str())
-            .unwrap();
+        let jackd_exited = jack_server.try_wait().inspect(|status| {
+            println!("jackd exited with {}", status);
+        });
+        let restart = match end {
+            // jackd may hang on to a vanished device, so always restart it
+            SessionEnd::DeviceLost => true,
+            _ => jackd_exited.is_some() && ctx.cfg.mic.restart_jackd,
+        };
+        if restart {
+            if !restart_jackd(&ctx.cfg, &mut jack_server, device_watch.as_ref(), &runtime, &shutdown) {
+                return;
+            }
+        } else if wait_for_shutdown(&shutdown, RESTART_INTERVAL) {
+            return;
+        }
     }
+}
 
-    for i in 0..cfg.speaker.n_channel {
-        active_client
-            .as_client()
-            .connect_ports_by_name(format!("rust_client:out_{i}").as_str(), &out_ports_name[i])
-            .unwrap();
-    }
-
-    if cfg.audio_connection.connect_mic_speaker
-        && cfg.mic.n_channel > cfg.audio_connection.mic_idx
-        && cfg.speaker.n_channel > cfg.audio_connection.speaker_idx
-    {
-        active_client
-            .as_client()
-            .connect_ports_by_name(
-                &in_ports_name[cfg.audio_connection.mic_idx].as_str(),
-                &out_ports_name[cfg.audio_connection.speaker_idx].as_str(),
-            )
-            .unwrap();
-    }
-
-    let _ = shutdown.recv();
-    println!("shutting down jack client");
-    active_client.deactivate().unwrap();
+// JACK time the first frame of this cycle's capture buffer was recorded. The
+// buffer ends at the start of the cycle and the capture latency of the port
+// (at least one period) says how long ago its first frame hit the converter.
+fn capture_time_micros(
+    ps: &jack::ProcessScope,
+    port: &jack::Port<jack::AudioIn>,
+    sample_rate: i64,
+) -> i64 {
+    let cycle_start = match ps.cycle_times() {
+        Ok(times) => times.current_usecs as i64,
+        Err(_) => {
+            jack::get_time() as i64 - ps.frames_since_cycle_start() as i64 * 1_000_000 / sample_rate
+        }
+    };
+    let (_, max_latency) = port.get_latency_range(jack::LatencyType::Capture);
+    let latency = max_latency.max(ps.n_frames()) as i64;
+    cycle_start - latency * 1_000_000 / sample_rate
 }
 
-#[inline(always)]
-fn slice_i16_to_u8(slice: &[i16]) -> &[u8] {
-    let byte_len = slice.len() * 2;
+pub(crate) fn slice_f32_to_u8(slice: &[f32]) -> &[u8] {
+    let byte_len = slice.len() * 4;
     unsafe { std::slice::from_raw_parts(slice.as_ptr().cast::<u8>(), byte_len) }
 }
 
-fn slice_i16_to_u8_mut(slice: &mut [i16]) -> &mut [u8] {
-    let byte_len = slice.len() * 2;
-    unsafe { std::slice::from_raw_parts_mut(slice.as_ptr().cast::<u8>().cast_mut(), byte_len) }
+pub(crate) fn slice_f32_to_u8_mut(slice: &mut [f32]) -> &mut [u8] {
+    let byte_len = slice.len() * 4;
+    unsafe { std::slice::from_raw_parts_mut(slice.as_mut_ptr().cast::<u8>(), byte_len) }
 }
 
-#[inline(always)]
-fn pcm_f32_to_i16(s: f32) -> i16 {
-    let mut i = (s * 32768.0).round() as i32;
-    if i > 32767 { i = 32767; }
-    if i < -32768 { i = -32768; }
-    i as i16
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-#[inline(always)]
-fn pcm_i16_to_f32(s: i16) -> f32 {
-    let mut f = s as f32 / 32768.0;
-    if f > 1.0 { f = 1.0; }
-    if f < -1.0 { f = -1.0; }
-    f
+    fn assert_gains(gains: &[f32], expected: &[f32]) {
+        assert_eq!(gains.len(), expected.len());
+        for (gain, expected) in gains.iter().zip(expected.iter()) {
+            assert!((gain - expected).abs() < 1e-5, "{gains:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn fade_waits_for_reserve_then_ramps_in() {
+        // 10 samples of fade and reserve
+        let mut fade = PlaybackFade::new(10, 1000);
+        let mut gains = [0.0; 8];
+        assert_eq!(fade.next(17, 8, &mut gains), 0);
+
+        assert_eq!(fade.next(18, 8, &mut gains), 8);
+        assert_gains(&gains, &[0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8]);
+        assert_eq!(fade.next(18, 8, &mut gains), 8);
+        assert_gains(&gains, &[0.9, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn fade_plays_out_what_is_left_and_turns_without_a_step() {
+        let mut fade = PlaybackFade::new(10, 1000);
+        let mut gains = [0.0; 8];
+        for _ in 0..2 {
+            fade.next(100, 8, &mut gains);
+        }
+
+        // running dry: the fade out takes what the ring holds, at its own pace
+        assert_eq!(fade.next(15, 8, &mut gains), 8);
+        assert_gains(&gains, &[0.9, 0.8, 0.7, 0.6, 0.5, 0.4, 0.3, 0.2]);
+        // the stream is back mid fade: up again from where the gain is
+        assert_eq!(fade.next(18, 8, &mut gains), 8);
+        assert_gains(&gains, &[0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0]);
+
+        // less left than the fade needs: steeper, and down to zero at the end
+        assert_eq!(fade.next(4, 8, &mut gains), 4);
+        assert_gains(&gains[..4], &[0.75, 0.5, 0.25, 0.0]);
+        assert_eq!(fade.next(0, 8, &mut gains), 0);
+    }
 }
@@ -2,96 +2,284 @@ type Error = Box<dyn std::error::Error + Send + Sync>;
 type Result<T> = std::result::Result<T, Error>;
 
 mod system_call;
-use system_call::start_jackd;
+use system_call::{wait_for_jackd, JackServer};
 mod jack_client;
-use jack_client::{inspect_device, start_jack_client};
+use jack_client::{
+    capture_ports, inspect_device, run_audio, slice_f32_to_u8, slice_f32_to_u8_mut, AudioContext, CaptureState,
+};
 mod config_file;
-use config_file::Config;
+use config_file::{Config, ReceiverMode, SampleFormat, VadSource};
+mod pcm;
+use pcm::{decode, Encoder};
 mod tcp_server;
 use tcp_server::start_server;
 mod ring_buf;
+mod packet_header;
+use packet_header::{PacketHeader, BASE_HEADER_LEN, FLAGS_HEADER_LEN, FLAG_DEVICE_DOWN, FLAG_SPEECH};
+mod audio_clock;
+use audio_clock::{AudioClock, CaptureGaps, SampleRates};
+mod timing;
+use timing::{run_timing, CaptureTime, ReferenceClock, TimeBase};
 mod tcp_client;
 use tcp_client::start_tcp_client;
+mod tcp_recv_server;
+use tcp_recv_server::start_recv_server;
+mod reconnect;
+mod tcp_pusher;
+use tcp_pusher::start_pushers;
+mod hub;
+mod hotplug;
+mod stream_info;
+mod routing;
+use routing::RoutingMatrix;
+mod control;
+mod fft;
+mod aec;
+mod agc;
+mod beamformer;
+mod doa;
+use doa::{run_doa, DoaEstimator};
+mod vad;
+use vad::{SpeechGate, VoiceDetector};
+mod conditioning;
+mod noise;
+use conditioning::channel_settings;
+mod processing;
+use processing::{run_pipeline, CaptureChunk, CapturePipeline, ProcessedPacket};
+mod resampler;
+use resampler::Resampler;
+use control::{start_control_server, Controls};
+use stream_info::{serve_stream_info, StreamInfo};
+use hub::run_hub;
+use reconnect::{log_connection_events, ConnectionMonitor};
 
 use std::cmp::{max, min};
 use std::io::Write;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 // use std::thread::JoinHandle;
-use std::time::{SystemTime, UNIX_EPOCH};
 use jack::{RingBufferReader, RingBufferWriter};
 use tokio::{self, sync::Notify};
-use tokio::time::{sleep, Duration};
+use tokio::time::{self as time, Duration, MissedTickBehavior};
 // use tokio::task::JoinHandle;
 use tokio::sync::{broadcast, mpsc};
 use crossbeam::channel::bounded;
-// use arc_swap::ArcSwap;
+use arc_swap::ArcSwap;
+
+// how long jackd may take to open the audio device
+const JACKD_READY_TIMEOUT: Duration = Duration::from_secs(10);
 
 
 #[tokio::main]
 async fn main() {
     let mut cfg = Arc::new(Config::new());
+    if cfg.hub.enabled {
+        run_hub(cfg).await;
+        return;
+    }
     let send_header_len = cfg.tcp_sender.header_len;
     let recv_header_len = cfg.tcp_receiver.header_len;
     let sample_per_send_packet = cfg.tcp_sender.sample_per_packet;
     let sample_per_recv_packet = cfg.tcp_receiver.sample_per_packet;
     let sample_per_packet = max(sample_per_send_packet, sample_per_recv_packet);
-    let packet_time_len = (sample_per_send_packet * 1000 / cfg.mic.sample_rate) as i16;
     let recv_n_ch = cfg.tcp_receiver.n_channel;
     let recv_pkt_len = recv_header_len + 
-        recv_n_ch * sample_per_recv_packet *2;
+        recv_n_ch * sample_per_recv_packet * cfg.tcp_receiver.sample_format.bytes();
     let device_id = cfg.mic.device_id as u16;
+    // packets and processing run at the stream rate, the audio callback at
+    // the device rate
+    let sample_rate = cfg.mic.stream_rate();
+    let rates = SampleRates {
+        device: cfg.mic.sample_rate,
+        stream: sample_rate,
+    };
+    if rates.device != rates.stream {
+        println!("Resample capture from {} Hz to {} Hz", rates.device, rates.stream);
+    }
+    assert!(send_header_len >= BASE_HEADER_LEN, "tcp_sender.header_len must be at least {BASE_HEADER_LEN}");
 
-    let cfg_cp = cfg.clone();
-    let _jack_server = start_jackd(cfg_cp);
-    sleep(Duration::from_millis(1500)).await;
+    let mut jack_server = match JackServer::start(cfg.clone()) {
+        Ok(jack_server) => jack_server,
+        Err(err) => {
+            println!("failed to start jackd! {}", err);
+            std::process::exit(1);
+        }
+    };
+    let client = match wait_for_jackd(&mut jack_server, JACKD_READY_TIMEOUT).await {
+        Ok(client) => client,
+        Err(err) => {
+            println!("{}", err);
+            drop(jack_server);
+            std::process::exit(1);
+        }
+    };
     // let cfg_cp = cfg.clone();
     // let _alsa_out = start_alsa_out(cfg_cp);
     // sleep(Duration::from_millis(500)).await;
  
-    let (client, mut n_mic, mut n_speaker) = inspect_device();
+    let (mut n_mic, mut n_speaker) = inspect_device(&client, &cfg);
     if n_mic < cfg.mic.n_channel {
         println!("n_mic set to {}", n_mic);
-        if let Some(mut cfg_mut) = Arc::<Config>::get_mut(&mut cfg) {
+        if let Some(cfg_mut) = Arc::<Config>::get_mut(&mut cfg) {
             cfg_mut.mic.n_channel = n_mic;
         }
     }
     n_speaker = min(n_speaker, cfg.tcp_receiver.n_channel);
     if n_speaker < cfg.speaker.n_channel {
         println!("n_speaker set to {}", n_speaker);
-        if let Some(mut cfg_mut) = Arc::<Config>::get_mut(&mut cfg) {
+        if let Some(cfg_mut) = Arc::<Config>::get_mut(&mut cfg) {
             cfg_mut.speaker.n_channel = n_speaker;
         }
     }
-    (n_mic, n_speaker) = (cfg.mic.n_channel, cfg.speaker.n_channel);
-    let n_ch = n_mic + n_speaker;
-    let send_pkt_len = send_header_len + sample_per_send_packet * n_ch * 2;
-    println!("Send {n_ch} channels with packet length {send_pkt_len}");
+    (n_mic, n_speaker) = (cfg.mic.total_channels(), cfg.speaker.n_channel);
 
     let mut capture_buf_readers = Vec::<RingBufferReader>::new();
     let mut capture_buf_writers = Vec::<RingBufferWriter>::new();
+    // reserve 0.5s of f32 samples for each mic, and at least a few periods
+    let capture_ring_len = max(cfg.mic.sample_rate * 2, cfg.mic.period * 16);
     for _ in 0..n_mic {
-        // reserve 0.5s buffer for each mic
-        let ringbuf = jack::RingBuffer::new(cfg.mic.sample_rate).unwrap();
+        let ringbuf = jack::RingBuffer::new(capture_ring_len).unwrap();
         let (reader, writer) = ringbuf.into_reader_writer();
         capture_buf_readers.push(reader);
         capture_buf_writers.push(writer);
     }
 
+    let beams = Arc::new(ArcSwap::from_pointee(cfg.beamformer.beams.clone()));
+    let channel_settings = Arc::new(ArcSwap::from_pointee(channel_settings(&cfg.conditioning, n_mic)));
+    let raw_port = cfg.noise_suppression.raw_port;
+    let pipeline = CapturePipeline::new(
+        &cfg,
+        n_mic,
+        n_speaker,
+        beams.clone(),
+        channel_settings.clone(),
+        raw_port != 0,
+    );
+    // beams not served on a port of their own follow the other channels;
+    // without mics there are none, whatever the config lists
+    let n_appended = if cfg.beamformer.listen_port == 0 { pipeline.n_beam() } else { 0 };
+    let n_ch = n_mic + n_speaker + n_appended;
+    let send_pkt_len = send_header_len + sample_per_send_packet * n_ch * cfg.tcp_sender.sample_format.bytes();
+    println!("Send {n_ch} channels with packet length {send_pkt_len}");
+
+    let stream_info = StreamInfo::new(&cfg, &capture_ports(&client, &cfg), pipeline.n_beam());
+    if cfg.tcp_sender.info_port != 0 {
+        let info_port = cfg.tcp_sender.info_port;
+        tokio::spawn(async move {
+            serve_stream_info(info_port, &stream_info, tokio::signal::ctrl_c()).await;
+        });
+    }
+
+    // the mic channels as captured, in packets like the main stream without beams
+    let raw_sender = (raw_port != 0).then(|| {
+        let (raw_sender, _) = broadcast::channel::<Vec<u8>>(16);
+        tokio::spawn(start_server(
+            raw_port,
+            cfg.noise_suppression.max_clients,
+            raw_sender.clone(),
+            tokio::signal::ctrl_c(),
+        ));
+        raw_sender
+    });
+    // beams on a port of their own, in packets with the same headers
+    let beam_sender = if pipeline.n_beam() > 0 && cfg.beamformer.listen_port != 0 {
+        let (beam_sender, _) = broadcast::channel::<Vec<u8>>(16);
+        tokio::spawn(start_server(
+            cfg.beamformer.listen_port,
+            cfg.beamformer.max_clients,
+            beam_sender.clone(),
+            tokio::signal::ctrl_c(),
+        ));
+        Some(beam_sender)
+    } else {
+        None
+    };
+
+    // direction of arrival over whole packets, on a thread of its own
+    let doa_window = (cfg.doa.window_ms * sample_rate / 1000).div_ceil(sample_per_send_packet) * sample_per_send_packet;
+    let doa_estimator = cfg
+        .doa
+        .enabled
+        .then(|| DoaEstimator::new(&cfg.doa, &cfg.beamformer, n_mic, sample_rate, doa_window))
+        .flatten();
+    if cfg.doa.enabled && doa_estimator.is_none() {
+        println!("doa: beamformer.mic_positions must place at least 2 mics, direction of arrival is off");
+    }
+    let doa_sender = doa_estimator.map(|estimator| {
+        let (doa_sender, doa_receiver) = bounded::<(PacketHeader, Vec<Vec<f32>>)>(64);
+        let (line_sender, _) = broadcast::channel::<Vec<u8>>(16);
+        tokio::spawn(start_server(
+            cfg.doa.listen_port,
+            cfg.doa.max_clients,
+            line_sender.clone(),
+            tokio::signal::ctrl_c(),
+        ));
+        std::thread::spawn(move || run_doa(estimator, doa_receiver, line_sender));
+        doa_sender
+    });
+
+    let vad_channel = match cfg.vad.source {
+        VadSource::Mic if cfg.vad.channel < n_mic => Some(cfg.vad.channel),
+        VadSource::Beam if cfg.vad.channel < pipeline.n_beam() => Some(n_mic + cfg.vad.channel),
+        _ => None,
+    };
+    if cfg.vad.enabled && vad_channel.is_none() {
+        println!("vad: no {:?} channel {}, voice activity detection is off", cfg.vad.source, cfg.vad.channel);
+    }
+    if cfg.vad.enabled && send_header_len < FLAGS_HEADER_LEN {
+        println!(
+            "vad: tcp_sender.header_len {} has no flags field, packets are not marked as speech",
+            send_header_len
+        );
+    }
+    let voice_detector = vad_channel
+        .filter(|_| cfg.vad.enabled)
+        .map(|channel| VoiceDetector::new(&cfg.vad, channel, sample_per_send_packet, sample_rate));
+    let speech_gate = (voice_detector.is_some() && cfg.vad.speech_port != 0).then(|| {
+        let (speech_sender, _) = broadcast::channel::<Vec<u8>>(64);
+        tokio::spawn(start_server(
+            cfg.vad.speech_port,
+            cfg.vad.max_clients,
+            speech_sender.clone(),
+            tokio::signal::ctrl_c(),
+        ));
+        let pre_roll_packets = (cfg.vad.pre_roll_ms * sample_rate / 1000).div_ceil(sample_per_send_packet);
+        SpeechGate::new(pre_roll_packets, speech_sender)
+    });
+    let mut echo_ref_readers = Vec::<RingBufferReader>::new();
+    let mut echo_ref_writers = Vec::<RingBufferWriter>::new();
+    if pipeline.uses_reference() {
+        for _ in 0..n_speaker {
+            let ringbuf = jack::RingBuffer::new(capture_ring_len).unwrap();
+            let (reader, writer) = ringbuf.into_reader_writer();
+            echo_ref_readers.push(reader);
+            echo_ref_writers.push(writer);
+        }
+    }
+
     let mut resend_buf_readers = Vec::<RingBufferReader>::new();
     let mut resend_buf_writers = Vec::<RingBufferWriter>::new();
+    // received samples are kept as f32 until they are sent or played
     for _ in 0..n_speaker {
-        let ringbuf = jack::RingBuffer::new(sample_per_packet * 8).unwrap();
+        let ringbuf = jack::RingBuffer::new(sample_per_packet * 16).unwrap();
         let (reader, writer) = ringbuf.into_reader_writer();
         resend_buf_readers.push(reader);
         resend_buf_writers.push(writer);
     }
 
 
+    // received streams come at the stream rate and play at the device rate
+    let playback_resampler = (rates.device != rates.stream)
+        .then(|| Resampler::new(rates.stream, rates.device, recv_n_ch));
+    let played_per_packet = playback_resampler
+        .as_ref()
+        .map_or(sample_per_packet, |resampler| resampler.max_output(sample_per_packet));
     let mut playback_buf_readers = Vec::<RingBufferReader>::new();
     let mut playback_buf_writers = Vec::<RingBufferWriter>::new();
-    for _ in 0..n_speaker {
-        let ringbuf = jack::RingBuffer::new(sample_per_packet * 8).unwrap();
+    // every received channel can be routed to the speakers
+    for _ in 0..recv_n_ch {
+        // the callback takes a whole period at once, which may exceed a packet
+        let ringbuf = jack::RingBuffer::new(max(played_per_packet, cfg.mic.period) * 16).unwrap();
         let (reader, writer) = ringbuf.into_reader_writer();
         playback_buf_readers.push(reader);
         playback_buf_writers.push(writer);
@@ -114,19 +302,56 @@ async fn main() {
 
     let (packet_sender, packet_receiver) = broadcast::channel(16);
     let pkt_sender = packet_sender.clone();
+    let push_sender = packet_sender.clone();
+
+    let audio_clock = Arc::new(AudioClock::new());
+    let capture_gaps = Arc::new(CaptureGaps::new());
+    let device_down = Arc::new(AtomicBool::new(false));
+    let reference_clock = ReferenceClock::open(&cfg.timing).unwrap_or_else(|err| {
+        println!("failed to open {:?} clock, use realtime clock! {}", cfg.timing.reference_clock, err);
+        ReferenceClock::realtime()
+    });
+    let time_base = Arc::new(TimeBase::new(reference_clock));
+    tokio::spawn(run_timing(
+        cfg.timing.clone(),
+        time_base.clone(),
+        audio_clock.clone(),
+        rates.device,
+    ));
+
+    // the capture pipeline on a thread of its own, fed with chunks of the
+    // capture rings and handing back whole packets
+    let n_beam = pipeline.n_beam();
+    let (chunk_sender, chunk_receiver) = bounded::<CaptureChunk>(64);
+    let (processed_sender, processed_receiver) = mpsc::channel::<ProcessedPacket>(16);
+    std::thread::spawn(move || run_pipeline(pipeline, chunk_receiver, processed_sender, sample_per_send_packet));
 
     let process_sender_buf = process_send_buf(
         notifyee_sound_ready,
+        audio_clock.clone(),
+        capture_gaps.clone(),
+        time_base,
+        device_down.clone(),
         send_pkt_len,
         sample_per_send_packet,
-        packet_time_len,
+        rates,
         device_id,
         send_header_len,
         n_speaker,
+        n_beam,
+        chunk_sender,
+        processed_receiver,
         capture_buf_readers,
+        echo_ref_readers,
         resend_buf_readers,
         packet_sender,
         packet_receiver,
+        beam_sender,
+        doa_sender,
+        voice_detector,
+        speech_gate,
+        raw_sender,
+        Encoder::new(cfg.tcp_sender.sample_format, cfg.tcp_sender.dither),
     );
 
     let process_receiver_buf = process_recv_buf(
@@ -135,6 +360,8 @@ async fn main() {
         n_speaker,
         recv_header_len,
         sample_per_recv_packet,
+        cfg.tcp_receiver.sample_format,
+        playback_resampler,
         resend_buf_writers,
         playback_buf_writers,
     );
@@ -150,51 +377,79 @@ async fn main() {
             tokio::signal::ctrl_c(),
         );
 
-    let cfg_cp = cfg.clone();
-    let recv_handler =
-        start_tcp_client(
-            cfg_cp,
-            resend,
-            tokio::signal::ctrl_c()
+    let push_handler =
+        start_pushers(
+            cfg.tcp_sender.push_targets.clone(),
+            cfg.tcp_sender.push_reconnect.clone(),
+            push_sender,
+            tokio::signal::ctrl_c(),
         );
 
     let cfg_cp = cfg.clone();
-    let jack_panic_flag = Arc::new(AtomicBool::new(false));
-    let jack_panic_flag_clone = jack_panic_flag.clone();
-    let audio_thread = std::thread::spawn(move || {
-        start_jack_client(
-            cfg_cp,
-            client,
-            notify_sound_ready,
-            capture_buf_writers,
-            playback_buf_readers,
-            shutdown_sync_r,
-            jack_panic_flag_clone
-        );
-    });
-
-    let shutdown_sender = shutdown_sync_s.clone();
-
-    tokio::spawn(async move {
-        loop {
-            if jack_panic_flag.load(Ordering::SeqCst) {
-                eprintln!("Critical JACK failure detected");
-                let _ = shutdown_sender.send(());
-                panic!("JACK subsystem failure");
+    let recv_handler = async move {
+        match cfg_cp.tcp_receiver.mode {
+            ReceiverMode::Connect => {
+                // only the client reports connection states
+                let recv_monitor = Arc::new(ConnectionMonitor::new("tcp_receiver"));
+                tokio::spawn(log_connection_events(
+                    recv_monitor.name().to_string(),
+                    recv_monitor.subscribe(),
+                    recv_monitor.metrics.clone(),
+                ));
+                start_tcp_client(
+                    cfg_cp,
+                    resend,
+                    recv_monitor,
+                    tokio::signal::ctrl_c()
+                ).await
+            }
+            ReceiverMode::Listen => {
+                start_recv_server(
+                    cfg_cp,
+                    resend,
+                    tokio::signal::ctrl_c()
+                ).await
             }
-            sleep(Duration::from_millis(100)).await;
         }
+    };
+
+    let routing = Arc::new(ArcSwap::from_pointee(RoutingMatrix::from_config(&cfg)));
+    if cfg.control.port != 0 {
+        let controls = Arc::new(Controls {
+            routing: routing.clone(),
+            beams,
+            channels: channel_settings,
+        });
+        tokio::spawn(start_control_server(cfg.control.clone(), controls, tokio::signal::ctrl_c()));
+    }
+
+    let audio_context = AudioContext {
+        cfg: cfg.clone(),
+        notifier: notify_sound_ready,
+        state: Arc::new(Mutex::new(CaptureState::new(
+            capture_buf_writers,
+            echo_ref_writers,
+            playback_buf_readers,
+        ))),
+        audio_clock,
+        capture_gaps,
+        device_down,
+        routing,
+    };
+    let runtime = tokio::runtime::Handle::current();
+    let audio_thread = std::thread::spawn(move || {
+        run_audio(audio_context, client, jack_server, runtime, shutdown_sync_r);
     });
 
     tokio::join!(
         send_handler,
+        push_handler,
         recv_handler,
         process_sender_buf,
         process_receiver_buf,
     );
 
     {
-        let _ = tokio::signal::ctrl_c();
         let _ = shutdown_sync_s.send(());
         println!("sent shutdown signal");
     }
@@ -205,119 +460,334 @@ async fn main() {
     // alsa_out.kill().await.expect("Kill alsa_out failed");
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn process_recv_buf(
     mut incoming_socket: mpsc::Receiver<Vec<u8>>,
     recv_pkt_len: usize,
     n_speaker: usize,
     recv_header_len: usize,
     sample_per_recv_packet: usize,
+    recv_format: SampleFormat,
+    mut resampler: Option<Resampler>,
     mut resend_buf_writers: Vec<RingBufferWriter>,
     mut playback_buf_writers: Vec<RingBufferWriter>,
 ) {
+    let n_channel = playback_buf_writers.len();
+    let mut samples = vec![vec![0_f32; sample_per_recv_packet]; n_channel];
+    let mut played = vec![Vec::new(); n_channel];
+    let played_per_packet = resampler
+        .as_ref()
+        .map_or(sample_per_recv_packet, |resampler| resampler.max_output(sample_per_recv_packet));
+    let channel_len = sample_per_recv_packet * recv_format.bytes();
     while let Some(received_buf) = incoming_socket.recv().await {
         assert_eq!(recv_pkt_len, received_buf.len());
-        if playback_buf_writers.len() ==0 ||
-        playback_buf_writers[0].space() < sample_per_recv_packet * 4 {
+        if playback_buf_writers.is_empty() ||
+        playback_buf_writers[0].space() < played_per_packet * 4 {
             continue;
         }
-        let _secs = u32::from_le_bytes(received_buf[2..6].try_into().unwrap());
-        let _ms = i16::from_le_bytes(received_buf[6..8].try_into().unwrap());
-        let _pkt_id = i32::from_le_bytes(received_buf[8..12].try_into().unwrap());
-        // println!("{}--{}--{}", _pkt_id, _secs, _ms);
-
-        for i in 0..n_speaker {
-            let s_idx = recv_header_len + sample_per_recv_packet * 2 * i;
-            let e_idx = s_idx + sample_per_recv_packet * 2;
-
-            resend_buf_writers[i].write_all(&received_buf[s_idx..e_idx]).unwrap();
-            playback_buf_writers[i].write_all(&received_buf[s_idx..e_idx]).unwrap();
+        let _header = PacketHeader::read_from(&received_buf[..recv_header_len]);
+        // println!("{:?}", _header);
+
+        for (i, channel) in samples.iter_mut().enumerate() {
+            let s_idx = recv_header_len + channel_len * i;
+            decode(recv_format, &received_buf[s_idx..s_idx + channel_len], channel);
+            if i < n_speaker {
+                resend_buf_writers[i].write_all(slice_f32_to_u8(channel)).unwrap();
+            }
+        }
+        let played = match resampler.as_mut() {
+            Some(resampler) => {
+                for channel in played.iter_mut() {
+                    channel.clear();
+                }
+                let input: Vec<&[f32]> = samples.iter().map(Vec::as_slice).collect();
+                resampler.process(&input, &mut played);
+                &played
+            }
+            None => &samples,
+        };
+        for (playback_buf_writer, channel) in playback_buf_writers.iter_mut().zip(played.iter()) {
+            playback_buf_writer.write_all(slice_f32_to_u8(channel)).unwrap();
         }
     }
     println!("Break recv loop");
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn process_send_buf(
     notifyee_sound_ready: Arc<Notify>,
+    audio_clock: Arc<AudioClock>,
+    capture_gaps: Arc<CaptureGaps>,
+    time_base: Arc<TimeBase>,
+    device_down: Arc<AtomicBool>,
     send_pkt_len: usize,
     sample_per_send_packet: usize,
-    packet_time_len: i16,
+    rates: SampleRates,
     device_id: u16,
     send_header_len: usize,
     n_speaker: usize,
+    n_beam: usize,
+    // the capture pipeline runs on a thread of its own
+    chunk_sender: crossbeam::channel::Sender<CaptureChunk>,
+    mut processed_receiver: mpsc::Receiver<ProcessedPacket>,
     mut capture_buf_readers: Vec<RingBufferReader>,
+    mut echo_ref_readers: Vec<RingBufferReader>,
     mut resend_buf_readers: Vec<RingBufferReader>,
     packet_sender: broadcast::Sender<Vec<u8>>,
-    mut packet_receiver: broadcast::Receiver<Vec<u8>>,
+    // only held, so sending never fails while no client is connected
+    _packet_receiver: broadcast::Receiver<Vec<u8>>,
+    beam_sender: Option<broadcast::Sender<Vec<u8>>>,
+    doa_sender: Option<crossbeam::channel::Sender<(PacketHeader, Vec<Vec<f32>>)>>,
+    mut voice_detector: Option<VoiceDetector>,
+    mut speech_gate: Option<SpeechGate>,
+    raw_sender: Option<broadcast::Sender<Vec<u8>>>,
+    mut encoder: Encoder,
 ) {
     tokio::select! {
         _ = async {
             let mut pkt_id = 0_i32;
+            // position of the next packet in the capture ring buffers, at the stream rate
+            let mut pkt_sample = 0_u64;
+            // capture sample index minus ring position in device samples, grows
+            // with every dropped cycle
+            let mut capture_offset = 0_u64;
+            let mut next_gap: Option<(u64, u64)> = None;
+            // capture sample index the next packet continues from
+            let mut next_sample_count = 0_u64;
+            let mut reported_overruns = 0_u64;
+            let mut max_backlog = 0_usize;
+            let n_mic = capture_buf_readers.len();
             let send_packet_buf = vec![0_u8; send_pkt_len];
-            let mut send_channel_buf = vec![0_u8; sample_per_send_packet * 2];
-            let zeroed_channel_buf = vec![0_u8; sample_per_send_packet *2];
+            let channel_len = sample_per_send_packet * encoder.sample_bytes();
+            let beam_packet_buf = vec![0_u8; send_header_len + channel_len * n_beam];
+            let mut send_samples = vec![0_f32; sample_per_send_packet];
+            let packet_micros = (sample_per_send_packet * 1_000_000 / rates.stream) as u64;
+            let mut device_check = time::interval(Duration::from_micros(packet_micros));
+            device_check.set_missed_tick_behavior(MissedTickBehavior::Skip);
             loop {
-                notifyee_sound_ready.notified().await;
+                let processed = tokio::select! {
+                    _ = notifyee_sound_ready.notified() => {
+                        let overruns = capture_gaps.overruns.load(Ordering::Relaxed);
+                        if overruns != reported_overruns {
+                            reported_overruns = overruns;
+                            println!(
+                                "capture overrun: {} cycles, {} samples dropped in total",
+                                overruns,
+                                capture_gaps.dropped_samples.load(Ordering::Relaxed)
+                            );
+                        }
+
+                        // the callback fills the rings one after the other, so only
+                        // take what every ring has; while the pipeline is behind,
+                        // the rings hold on to the rest and overrun if need be
+                        while !chunk_sender.is_full() {
+                            let n_captured = capture_buf_readers
+                                .iter()
+                                .chain(echo_ref_readers.iter())
+                                .map(|reader| reader.space() / 4)
+                                .min()
+                                .unwrap_or(0)
+                                .min(sample_per_send_packet);
+                            if n_captured == 0 {
+                                break;
+                            }
+                            let mut read_chunk = |reader: &mut RingBufferReader| {
+                                let mut chunk = vec![0_f32; n_captured];
+                                reader.read_buffer(slice_f32_to_u8_mut(&mut chunk));
+                                chunk
+                            };
+                            let mics = capture_buf_readers.iter_mut().map(&mut read_chunk).collect();
+                            let references = echo_ref_readers.iter_mut().map(&mut read_chunk).collect();
+                            let _ = chunk_sender.try_send(CaptureChunk { mics, references });
+                        }
+                        let backlog = chunk_sender.len();
+                        if backlog > max_backlog {
+                            max_backlog = backlog;
+                            println!("capture pipeline backlog reached {} chunks", backlog);
+                        }
+                        // without mics there is nothing to process, packets
+                        // follow the notifications
+                        if !capture_buf_readers.is_empty() {
+                            continue;
+                        }
+                        ProcessedPacket { channels: Vec::new(), raw: Vec::new() }
+                    }
+                    processed = processed_receiver.recv() => match processed {
+                        Some(processed) => processed,
+                        None => break,
+                    },
+                    _ = device_check.tick() => {
+                        if !device_down.load(Ordering::Acquire) {
+                            continue;
+                        }
+                        // keep the stream going with silence while the device is
+                        // down, paced by the last clock reference; it takes over
+                        // from the first sample not sent yet
+                        if let Some(missing) = pop_gaps(&capture_gaps, &mut next_gap, rates.device_sample(pkt_sample)) {
+                            capture_offset = missing;
+                        }
+                        next_sample_count = next_sample_count.max(rates.stream_sample_after(pkt_sample, capture_offset));
+                        let clock = audio_clock.load();
+                        let now_micros = jack::get_time() as i64;
+                        while clock.jack_micros != 0
+                            && clock.jack_micros_at(rates.device_sample(next_sample_count + sample_per_send_packet as u64), rates.device) <= now_micros
+                        {
+                            let mut swap_buf_mut = send_packet_buf.clone();
+                            let capture = time_base.capture_time(clock.jack_micros_at(rates.device_sample(next_sample_count), rates.device));
+                            let mut header = packet_header(device_id, pkt_id, next_sample_count, capture);
+                            header.flags = FLAG_DEVICE_DOWN;
+                            header.write_to(&mut swap_buf_mut[..send_header_len]);
+                            // the other streams get the same silence, laid out as usual
+                            if let Some(raw_sender) = raw_sender.as_ref() {
+                                let raw_len = send_header_len + channel_len * (n_mic + n_speaker);
+                                let _ = raw_sender.send(swap_buf_mut[..raw_len].to_vec());
+                            }
+                            if let Some(beam_sender) = beam_sender.as_ref() {
+                                let mut beam_buf = beam_packet_buf.clone();
+                                header.write_to(&mut beam_buf[..send_header_len]);
+                                let _ = beam_sender.send(beam_buf);
+                            }
+                            if let Some(gate) = speech_gate.as_mut() {
+                                gate.push_device_down(&swap_buf_mut);
+                            }
+                            if packet_sender.send(swap_buf_mut).is_err() {
+                                print!("Broadcast packet failed");
+                            }
+                            next_sample_count += sample_per_send_packet as u64;
+                            pkt_id = next_pkt_id(pkt_id);
+                        }
+                        continue;
+                    }
+                };
+
+                if let Some(missing) = pop_gaps(&capture_gaps, &mut next_gap, rates.device_sample(pkt_sample)) {
+                    capture_offset = missing;
+                }
+                let capture_sample = rates.stream_sample_after(pkt_sample, capture_offset);
+                // captured before the device went down but covered by the
+                // silence sent since; sample counters only go up
+                if capture_sample < next_sample_count {
+                    pkt_sample += sample_per_send_packet as u64;
+                    continue;
+                }
 
                 // let swap_buf_mut = Arc::get_mut(&mut swap_buf).unwrap();
                 let mut swap_buf_mut = send_packet_buf.clone();
 
-                let unix_time_in_millis = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis()
-                    - 10;
-                let device_id = device_id;
-                let mut secs = (unix_time_in_millis / 1000) as u32;
-                let mut ms = (unix_time_in_millis % 1000) as i16 - packet_time_len;
-                if ms < 0 {
-                    secs -= 1;
-                    ms = ms + 1000;
+                // stamp the packet with the capture time of its first sample
+                let capture_device_sample = rates.device_sample(pkt_sample) + capture_offset;
+                let jack_micros = audio_clock.load().jack_micros_at(capture_device_sample, rates.device);
+                let capture = time_base.capture_time(jack_micros);
+                let mut header = packet_header(device_id, pkt_id, capture_sample, capture);
+                let mut speech = false;
+                if let Some(detector) = voice_detector.as_mut() {
+                    speech = detector.detect(&processed.channels[detector.channel()]);
+                }
+                if speech {
+                    header.flags |= FLAG_SPEECH;
                 }
+                header.write_to(&mut swap_buf_mut[..send_header_len]);
 
-                swap_buf_mut[0..2].copy_from_slice(&device_id.to_le_bytes());
-                swap_buf_mut[2..6].copy_from_slice(&secs.to_le_bytes());
-                swap_buf_mut[6..8].copy_from_slice(&ms.to_le_bytes());
-                swap_buf_mut[8..12].copy_from_slice(&pkt_id.to_le_bytes());
-        
                 let mut s_idx = send_header_len;
-                for i in 0..capture_buf_readers.len() {
-                    let n_bytes = capture_buf_readers[i].read_buffer(send_channel_buf.as_mut_slice());
-                    assert_eq!(n_bytes, send_channel_buf.len());
-                    let e_idx = s_idx + send_channel_buf.len();
+                for mic in processed.channels[..n_mic].iter() {
+                    let e_idx = s_idx + channel_len;
+                    encoder.encode(mic, &mut swap_buf_mut[s_idx..e_idx]);
+                    s_idx += channel_len;
+                }
+                if let Some(doa_sender) = doa_sender.as_ref() {
+                    // the estimator falls behind rather than the stream
+                    let _ = doa_sender.try_send((header, processed.channels[..n_mic].to_vec()));
+                }
 
-                    swap_buf_mut[s_idx..e_idx].copy_from_slice(send_channel_buf.as_ref());
-                    s_idx += send_channel_buf.len();
+                // the packet buffer starts out as silence
+                let resend_ready = resend_buf_readers
+                    .first()
+                    .is_some_and(|reader| reader.space() >= sample_per_send_packet * 4);
+                for reader in resend_buf_readers.iter_mut().take(n_speaker) {
+                    let e_idx = s_idx + channel_len;
+                    if resend_ready {
+                        let n_bytes = reader.read_buffer(slice_f32_to_u8_mut(&mut send_samples));
+                        assert_eq!(n_bytes, sample_per_send_packet * 4);
+                        encoder.encode(&send_samples, &mut swap_buf_mut[s_idx..e_idx]);
+                    }
+                    s_idx += channel_len;
                 }
 
-                for i in 0..n_speaker {
-                    let e_idx = s_idx + send_channel_buf.len();
-                    if resend_buf_readers[0].space() < sample_per_send_packet * 2 {
-                        swap_buf_mut[s_idx..e_idx].copy_from_slice(zeroed_channel_buf.as_ref());
-                    } else {
-                        let n_bytes = resend_buf_readers[i].read_buffer(send_channel_buf.as_mut());
-                        assert_eq!(n_bytes, send_channel_buf.len());
-                        swap_buf_mut[s_idx..e_idx].copy_from_slice(send_channel_buf.as_ref());
+                if let Some(raw_sender) = raw_sender.as_ref() {
+                    let mut raw_buf = swap_buf_mut[..s_idx].to_vec();
+                    for (mic, chunk) in processed.raw.iter().zip(raw_buf[send_header_len..].chunks_exact_mut(channel_len)) {
+                        encoder.encode(mic, chunk);
                     }
-                    s_idx += send_channel_buf.len();
+                    let _ = raw_sender.send(raw_buf);
+                }
+
+                if let Some(beam_sender) = beam_sender.as_ref() {
+                    let mut beam_buf = beam_packet_buf.clone();
+                    header.write_to(&mut beam_buf[..send_header_len]);
+                    for (beam, chunk) in processed.channels[n_mic..].iter().zip(beam_buf[send_header_len..].chunks_exact_mut(channel_len)) {
+                        encoder.encode(beam, chunk);
+                    }
+                    let _ = beam_sender.send(beam_buf);
+                } else {
+                    for beam in processed.channels[n_mic..].iter() {
+                        let e_idx = s_idx + channel_len;
+                        encoder.encode(beam, &mut swap_buf_mut[s_idx..e_idx]);
+                        s_idx += channel_len;
+                    }
+                }
+
+                if let Some(gate) = speech_gate.as_mut() {
+                    gate.push(&swap_buf_mut, speech);
                 }
 
                 // swap_buf = sender_buf.swap(swap_buf);
                 // notify_packet_ready.notify_waiters();
                 let res = packet_sender.send(swap_buf_mut);
-                if let Err(_) = res {
+                if res.is_err() {
                     print!("Broadcast packet failed");
                 }
 
-                let _ = packet_receiver.recv();
-        
-                pkt_id += 1;
-                if pkt_id == std::i32::MAX {
-                    pkt_id = 0;
-                }
+                pkt_sample += sample_per_send_packet as u64;
+                next_sample_count = capture_sample + sample_per_send_packet as u64;
+                pkt_id = next_pkt_id(pkt_id);
             }
         } => {}
         _ = tokio::signal::ctrl_c() => {
             println!("Break send loop");
         }
     }
-}
\ No newline at end of file
+}
+
+// Missing samples up to the last gap recorded at or before 'ring_sample'
+// (device rate), if one was reached since the last call.
+fn pop_gaps(capture_gaps: &CaptureGaps, next_gap: &mut Option<(u64, u64)>, ring_sample: u64) -> Option<u64> {
+    let mut missing = None;
+    while let Some(gap) = next_gap.take().or_else(|| capture_gaps.pop()) {
+        if gap.0 > ring_sample {
+            *next_gap = Some(gap);
+            break;
+        }
+        missing = Some(gap.1);
+    }
+    missing
+}
+
+fn packet_header(device_id: u16, pkt_id: i32, capture_sample: u64, capture: CaptureTime) -> PacketHeader {
+    let mut header = PacketHeader::from_unix_millis(
+        device_id,
+        capture.wall_ns.div_euclid(1_000_000),
+        pkt_id,
+    );
+    header.sample_count = capture_sample;
+    header.capture_ns = capture.wall_ns;
+    header.reference_ns = capture.reference_ns;
+    header
+}
+
+fn next_pkt_id(pkt_id: i32) -> i32 {
+    if pkt_id == i32::MAX - 1 {
+        0
+    } else {
+        pkt_id + 1
+    }
+}
@@ -0,0 +1,184 @@
+use crate::config_file::NoiseSuppressionConfig;
+use crate::fft::{Complex, Fft};
+use std::f32::consts::PI;
+
+// smoothing of the power spectrum the noise minimum is taken from
+const POWER_SMOOTHING: f32 = 0.7;
+// the minimum of the smoothed spectrum sits below the mean noise power
+const MINIMUM_BIAS: f32 = 2.0;
+// weight of the previous frame in the decision directed a priori SNR
+const DECISION_DIRECTED: f32 = 0.98;
+
+// Spectral noise suppression on frames of two blocks (square root Hann
+// window, 50% overlap-add), like the beamformer. Every bin is scaled by the
+// Wiener gain of its a priori SNR. The output of a frame completes the block
+// before the newest one, so channels without suppression are delayed by a
+// block to stay aligned.
+pub struct NoiseSuppressor {
+    block: usize,
+    fft: Fft,
+    window: Vec<f32>,
+    min_gain: f32,
+    noise_rise: f32,
+    // per channel, None when the channel is passed through
+    channels: Vec<Option<ChannelNoise>>,
+    previous: Vec<Vec<f32>>,
+    scratch: Vec<Complex>,
+    primed: bool,
+}
+
+struct ChannelNoise {
+    smoothed: Vec<f32>,
+    noise: Vec<f32>,
+    // |clean|² of the last frame
+    clean: Vec<f32>,
+    overlap: Vec<f32>,
+    started: bool,
+}
+
+impl NoiseSuppressor {
+    // 'selected' marks the channels to suppress noise on.
+    pub fn new(cfg: &NoiseSuppressionConfig, block: usize, sample_rate: usize, selected: &[bool]) -> NoiseSuppressor {
+        let frame_len = 2 * block;
+        let n_bin = block + 1;
+        NoiseSuppressor {
+            block,
+            fft: Fft::new(frame_len),
+            window: (0..frame_len)
+                .map(|i| (0.5 - 0.5 * (2.0 * PI * i as f32 / frame_len as f32).cos()).sqrt())
+                .collect(),
+            min_gain: 10_f32.powf(-cfg.max_attenuation_db.abs() / 20.0),
+            noise_rise: 10_f32.powf(cfg.noise_rise_db / 10.0 * block as f32 / sample_rate as f32),
+            channels: selected
+                .iter()
+                .map(|&selected| {
+                    selected.then(|| ChannelNoise {
+                        smoothed: vec![0.0; n_bin],
+                        noise: vec![0.0; n_bin],
+                        clean: vec![0.0; n_bin],
+                        overlap: vec![0.0; block],
+                        started: false,
+                    })
+                })
+                .collect(),
+            previous: vec![vec![0.0; block]; selected.len()],
+            scratch: vec![Complex::ZERO; frame_len],
+            primed: false,
+        }
+    }
+
+    // One block of every channel in, the block before it out; false for the
+    // very first block, which only primes the frames.
+    pub fn process(&mut self, channels: &[&[f32]], out: &mut [Vec<f32>]) -> bool {
+        let block = self.block;
+        for (((state, previous), input), out) in self
+            .channels
+            .iter_mut()
+            .zip(self.previous.iter_mut())
+            .zip(channels.iter())
+            .zip(out.iter_mut())
+        {
+            let Some(state) = state.as_mut() else {
+                out.copy_from_slice(previous);
+                previous.copy_from_slice(input);
+                continue;
+            };
+            for (i, x) in self.scratch.iter_mut().enumerate() {
+                let sample = if i < block { previous[i] } else { input[i - block] };
+                *x = Complex::new(sample * self.window[i], 0.0);
+            }
+            previous.copy_from_slice(input);
+            self.fft.forward(&mut self.scratch);
+
+            for k in 0..=block {
+                let power = self.scratch[k].norm_sqr();
+                if state.started {
+                    state.smoothed[k] = POWER_SMOOTHING * state.smoothed[k] + (1.0 - POWER_SMOOTHING) * power;
+                    state.noise[k] = state.smoothed[k].min(state.noise[k] * self.noise_rise);
+                } else {
+                    state.smoothed[k] = power;
+                    state.noise[k] = power;
+                }
+                let noise = state.noise[k] * MINIMUM_BIAS + 1e-20;
+                let posterior = power / noise;
+                let prior = DECISION_DIRECTED * state.clean[k] / noise
+                    + (1.0 - DECISION_DIRECTED) * (posterior - 1.0).max(0.0);
+                let gain = (prior / (1.0 + prior)).max(self.min_gain);
+                state.clean[k] = gain * gain * power;
+                self.scratch[k] = self.scratch[k].scale(gain);
+                if k > 0 && k < block {
+                    self.scratch[2 * block - k] = self.scratch[k].conj();
+                }
+            }
+            state.started = true;
+            self.fft.inverse(&mut self.scratch);
+            for (i, out) in out.iter_mut().enumerate() {
+                *out = state.overlap[i] + self.scratch[i].re * self.window[i];
+                state.overlap[i] = self.scratch[block + i].re * self.window[block + i];
+            }
+        }
+        let primed = self.primed;
+        self.primed = true;
+        primed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = 128;
+    const SAMPLE_RATE: usize = 16000;
+
+    fn energy(samples: &[f32]) -> f32 {
+        samples.iter().map(|x| x * x).sum()
+    }
+
+    #[test]
+    fn keeps_sample_count_and_order() {
+        // noise throughout, a loud tone in the second half
+        let n = SAMPLE_RATE * 2;
+        let mut seed = 1_u32;
+        let input: Vec<f32> = (0..n)
+            .map(|t| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                let noise = (seed as f32 / u32::MAX as f32 * 2.0 - 1.0) * 0.01;
+                let tone = (2.0 * PI * 1000.0 * t as f32 / SAMPLE_RATE as f32).sin() * 0.5;
+                if t < n / 2 {
+                    noise
+                } else {
+                    noise + tone
+                }
+            })
+            .collect();
+
+        let cfg = NoiseSuppressionConfig::default();
+        let mut suppressor = NoiseSuppressor::new(&cfg, BLOCK, SAMPLE_RATE, &[true, false]);
+        let mut block_out = vec![vec![0.0; BLOCK]; 2];
+        let mut output = vec![Vec::new(); 2];
+        for (i, block) in input.chunks_exact(BLOCK).enumerate() {
+            assert_eq!(suppressor.process(&[block, block], &mut block_out), i > 0);
+            if i > 0 {
+                for (output, block_out) in output.iter_mut().zip(block_out.iter()) {
+                    output.extend_from_slice(block_out);
+                }
+            }
+        }
+
+        // every block but the last is out, in order, one block later
+        let (suppressed, passed) = (&output[0], &output[1]);
+        assert_eq!(passed, &input[..n - BLOCK]);
+        assert_eq!(suppressed.len(), n - BLOCK);
+        let noise = n / 4..n / 2;
+        assert!(energy(&suppressed[noise.clone()]) < energy(&input[noise]) * 0.25);
+        let tone = n * 3 / 4..n - BLOCK;
+        let error: Vec<f32> = suppressed[tone.clone()]
+            .iter()
+            .zip(input[tone.clone()].iter())
+            .map(|(a, b)| a - b)
+            .collect();
+        assert!(energy(&error) < energy(&input[tone]) * 0.01);
+    }
+}
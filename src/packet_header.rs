@@ -0,0 +1,140 @@
+// Header at the start of every packet (little endian):
+//   0..2   device_id  u16
+//   2..6   unix time  u32, seconds
+//   6..8   ms         i16, milliseconds within the second
+//   8..12  pkt_id     i32
+// followed by optional fields, written only when header_len leaves room:
+//   12..20 sample_count u64, index of the first sample since capture started
+//   20..28 capture_ns   i64, unix time (CLOCK_REALTIME) of the first sample in ns
+//   28..36 reference_ns i64, time of the first sample on the reference clock
+//                       (CLOCK_TAI or a PTP hardware clock) in ns
+//   36..38 flags        u16, FLAG_* bits
+pub const BASE_HEADER_LEN: usize = 12;
+pub const SAMPLE_COUNT_HEADER_LEN: usize = 20;
+pub const TIMING_HEADER_LEN: usize = 36;
+pub const FLAGS_HEADER_LEN: usize = 38;
+
+// the audio device was down; the samples are silence filled in by the sender
+pub const FLAG_DEVICE_DOWN: u16 = 0x0001;
+// voice activity detected in the packet (or within the hang time before it)
+pub const FLAG_SPEECH: u16 = 0x0002;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PacketHeader {
+    pub device_id: u16,
+    pub secs: u32,
+    pub ms: i16,
+    pub pkt_id: i32,
+    pub sample_count: u64,
+    pub capture_ns: i64,
+    pub reference_ns: i64,
+    pub flags: u16,
+}
+
+impl PacketHeader {
+    pub fn from_unix_millis(device_id: u16, unix_millis: i64, pkt_id: i32) -> PacketHeader {
+        PacketHeader {
+            device_id,
+            secs: unix_millis.div_euclid(1000) as u32,
+            ms: unix_millis.rem_euclid(1000) as i16,
+            pkt_id,
+            ..PacketHeader::default()
+        }
+    }
+
+    pub fn unix_millis(&self) -> i64 {
+        self.secs as i64 * 1000 + self.ms as i64
+    }
+
+    // 'buf' is the header only; fields that do not fit are left at their default.
+    pub fn read_from(buf: &[u8]) -> PacketHeader {
+        let mut header = PacketHeader {
+            device_id: u16::from_le_bytes(buf[0..2].try_into().unwrap()),
+            secs: u32::from_le_bytes(buf[2..6].try_into().unwrap()),
+            ms: i16::from_le_bytes(buf[6..8].try_into().unwrap()),
+            pkt_id: i32::from_le_bytes(buf[8..12].try_into().unwrap()),
+            ..PacketHeader::default()
+        };
+        if buf.len() >= SAMPLE_COUNT_HEADER_LEN {
+            header.sample_count = u64::from_le_bytes(buf[12..20].try_into().unwrap());
+        }
+        if buf.len() >= TIMING_HEADER_LEN {
+            header.capture_ns = i64::from_le_bytes(buf[20..28].try_into().unwrap());
+            header.reference_ns = i64::from_le_bytes(buf[28..36].try_into().unwrap());
+        }
+        if buf.len() >= FLAGS_HEADER_LEN {
+            header.flags = u16::from_le_bytes(buf[36..38].try_into().unwrap());
+        }
+        header
+    }
+
+    // 'buf' is the header only; fields that do not fit are skipped.
+    pub fn write_to(&self, buf: &mut [u8]) {
+        buf[0..2].copy_from_slice(&self.device_id.to_le_bytes());
+        buf[2..6].copy_from_slice(&self.secs.to_le_bytes());
+        buf[6..8].copy_from_slice(&self.ms.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.pkt_id.to_le_bytes());
+        if buf.len() >= SAMPLE_COUNT_HEADER_LEN {
+            buf[12..20].copy_from_slice(&self.sample_count.to_le_bytes());
+        }
+        if buf.len() >= TIMING_HEADER_LEN {
+            buf[20..28].copy_from_slice(&self.capture_ns.to_le_bytes());
+            buf[28..36].copy_from_slice(&self.reference_ns.to_le_bytes());
+        }
+        if buf.len() >= FLAGS_HEADER_LEN {
+            buf[36..38].copy_from_slice(&self.flags.to_le_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_base_header() {
+        let header = PacketHeader::from_unix_millis(7, 1_700_000_000_123, 42);
+        let mut buf = [0_u8; BASE_HEADER_LEN];
+        header.write_to(&mut buf);
+        let read = PacketHeader::read_from(&buf);
+        assert_eq!(read, header);
+        assert_eq!(read.unix_millis(), 1_700_000_000_123);
+    }
+
+    #[test]
+    fn round_trips_sample_count() {
+        let mut header = PacketHeader::from_unix_millis(7, 1_700_000_000_123, 42);
+        header.sample_count = 1 << 40;
+        let mut buf = [0_u8; SAMPLE_COUNT_HEADER_LEN];
+        header.write_to(&mut buf);
+        assert_eq!(PacketHeader::read_from(&buf), header);
+        // a shorter header drops the counter
+        assert_eq!(PacketHeader::read_from(&buf[..BASE_HEADER_LEN]).sample_count, 0);
+    }
+
+    #[test]
+    fn round_trips_capture_times() {
+        let mut header = PacketHeader::from_unix_millis(7, 1_700_000_000_123, 42);
+        header.sample_count = 1 << 40;
+        header.capture_ns = 1_700_000_000_123_456_789;
+        header.reference_ns = -5;
+        let mut buf = [0_u8; TIMING_HEADER_LEN];
+        header.write_to(&mut buf);
+        assert_eq!(PacketHeader::read_from(&buf), header);
+        let short = PacketHeader::read_from(&buf[..SAMPLE_COUNT_HEADER_LEN]);
+        assert_eq!((short.sample_count, short.capture_ns), (1 << 40, 0));
+    }
+
+    #[test]
+    fn round_trips_flags() {
+        let mut header = PacketHeader::from_unix_millis(7, 1_700_000_000_123, 42);
+        header.sample_count = 1 << 40;
+        header.capture_ns = 1_700_000_000_123_456_789;
+        header.reference_ns = -5;
+        header.flags = FLAG_DEVICE_DOWN;
+        let mut buf = [0_u8; FLAGS_HEADER_LEN];
+        header.write_to(&mut buf);
+        assert_eq!(PacketHeader::read_from(&buf), header);
+        assert_eq!(PacketHeader::read_from(&buf[..TIMING_HEADER_LEN]).flags, 0);
+    }
+}
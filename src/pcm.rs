@@ -0,0 +1,137 @@
+use crate::config_file::SampleFormat;
+
+// Quantizes f32 samples into packets of 'format'. With dither, i16 samples
+// get triangular (TPDF) noise of one LSB peak added before rounding, which
+// keeps quiet signals from turning into distortion; i24 and f32 never get it.
+pub struct Encoder {
+    format: SampleFormat,
+    dither: bool,
+    // xorshift32 state
+    seed: u32,
+}
+
+impl Encoder {
+    pub fn new(format: SampleFormat, dither: bool) -> Encoder {
+        Encoder {
+            format,
+            dither: dither && format == SampleFormat::I16,
+            seed: 0x9e37_79b9,
+        }
+    }
+
+    pub fn sample_bytes(&self) -> usize {
+        self.format.bytes()
+    }
+
+    // out.len() must be samples.len() * format.bytes().
+    pub fn encode(&mut self, samples: &[f32], out: &mut [u8]) {
+        match self.format {
+            SampleFormat::I16 => {
+                for (o, &s) in out.chunks_exact_mut(2).zip(samples.iter()) {
+                    let d = if self.dither { self.tpdf() } else { 0.0 };
+                    let i = (s * 32768.0 + d).round() as i32;
+                    o.copy_from_slice(&(i.clamp(-32768, 32767) as i16).to_le_bytes());
+                }
+            }
+            SampleFormat::I24 => {
+                for (o, &s) in out.chunks_exact_mut(3).zip(samples.iter()) {
+                    let i = ((s as f64 * 8_388_608.0).round() as i32).clamp(-8_388_608, 8_388_607);
+                    o.copy_from_slice(&i.to_le_bytes()[..3]);
+                }
+            }
+            SampleFormat::F32 => {
+                for (o, &s) in out.chunks_exact_mut(4).zip(samples.iter()) {
+                    o.copy_from_slice(&s.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    // difference of two uniform values in [0, 1), in LSB
+    fn tpdf(&mut self) -> f32 {
+        self.uniform() - self.uniform()
+    }
+
+    fn uniform(&mut self) -> f32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        (self.seed >> 8) as f32 / (1 << 24) as f32
+    }
+}
+
+// Samples of 'format' in 'bytes' to f32; out.len() samples are decoded.
+pub fn decode(format: SampleFormat, bytes: &[u8], out: &mut [f32]) {
+    match format {
+        SampleFormat::I16 => {
+            for (o, b) in out.iter_mut().zip(bytes.chunks_exact(2)) {
+                *o = i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0;
+            }
+        }
+        SampleFormat::I24 => {
+            for (o, b) in out.iter_mut().zip(bytes.chunks_exact(3)) {
+                // shift the sign bit into place, then back
+                let i = i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8;
+                *o = i as f32 / 8_388_608.0;
+            }
+        }
+        SampleFormat::F32 => {
+            for (o, b) in out.iter_mut().zip(bytes.chunks_exact(4)) {
+                *o = f32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(format: SampleFormat, dither: bool, samples: &[f32]) -> Vec<f32> {
+        let mut bytes = vec![0; samples.len() * format.bytes()];
+        Encoder::new(format, dither).encode(samples, &mut bytes);
+        let mut out = vec![0.0; samples.len()];
+        decode(format, &bytes, &mut out);
+        out
+    }
+
+    fn ramp() -> Vec<f32> {
+        (0..=200).map(|i| (i as f32 - 100.0) / 101.0).collect()
+    }
+
+    #[test]
+    fn round_trips() {
+        let samples = ramp();
+        for (format, lsb) in [
+            (SampleFormat::I16, 1.0 / 32768.0),
+            (SampleFormat::I24, 1.0 / 8_388_608.0),
+            (SampleFormat::F32, 0.0),
+        ] {
+            for (&s, &o) in samples.iter().zip(round_trip(format, false, &samples).iter()) {
+                assert!((s - o).abs() <= lsb / 2.0, "{format:?}: {s} -> {o}");
+            }
+        }
+        // one LSB of triangular noise at most
+        for (&s, &o) in samples.iter().zip(round_trip(SampleFormat::I16, true, &samples).iter()) {
+            assert!((s - o).abs() <= 1.5 / 32768.0, "dithered: {s} -> {o}");
+        }
+    }
+
+    #[test]
+    fn i24_sign_extends() {
+        let mut out = [0.0; 3];
+        decode(SampleFormat::I24, &[0xff, 0xff, 0xff, 0x00, 0x00, 0x80, 0xff, 0xff, 0x7f], &mut out);
+        assert_eq!(out, [-1.0 / 8_388_608.0, -1.0, 8_388_607.0 / 8_388_608.0]);
+    }
+
+    #[test]
+    fn clamps_at_full_scale() {
+        let samples = [1.0, 2.0, -1.0, -2.0];
+        let mut bytes = [0; 8];
+        Encoder::new(SampleFormat::I16, false).encode(&samples, &mut bytes);
+        assert_eq!(bytes, [0xff, 0x7f, 0xff, 0x7f, 0x00, 0x80, 0x00, 0x80]);
+        let mut bytes = [0; 12];
+        Encoder::new(SampleFormat::I24, false).encode(&samples, &mut bytes);
+        assert_eq!(bytes, [0xff, 0xff, 0x7f, 0xff, 0xff, 0x7f, 0x00, 0x00, 0x80, 0x00, 0x00, 0x80]);
+    }
+}
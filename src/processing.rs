@@ -0,0 +1,272 @@
+use crate::aec::EchoCanceller;
+use crate::agc::GainControl;
+use crate::beamformer::Beamformer;
+use crate::conditioning::{ChannelSettings, Conditioner};
+use crate::noise::NoiseSuppressor;
+use crate::config_file::{BeamConfig, Config};
+use crate::resampler::Resampler;
+use arc_swap::ArcSwap;
+use crossbeam::channel::Receiver;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+// Processing of the captured mic channels between the capture rings and the
+// packets. Stages work on blocks of 'block_len' samples, so samples come out
+// up to three blocks (plus the limiter look-ahead) later than they went in;
+// every sample that goes in comes out, in order, which keeps ring positions
+// and sample counters valid.
+// When the stream rate differs from the device rate, mic and reference
+// channels are resampled first and every stage runs at the stream rate;
+// stream sample n then stands for device sample n * device / stream rate.
+// Output channels are the mics followed by the beams. When asked for, the
+// mics are also kept as they went in; as nothing is added or dropped, taking
+// as many of those as of the processed samples keeps both in step.
+pub struct CapturePipeline {
+    block_len: usize,
+    resampler: Option<Resampler>,
+    // mic then reference channels at the stream rate
+    resampled: Vec<Vec<f32>>,
+    conditioner: Conditioner,
+    aec: Option<EchoCanceller>,
+    beamformer: Option<Beamformer>,
+    tail: TailStages,
+    raw: Option<Vec<VecDeque<f32>>>,
+    input: Vec<Vec<f32>>,
+    reference: Vec<Vec<f32>>,
+    block: Vec<Vec<f32>>,
+    ref_block: Vec<Vec<f32>>,
+    // mic block waiting for the beams that complete it
+    delayed: Option<Vec<Vec<f32>>>,
+    beam_block: Vec<Vec<f32>>,
+}
+
+// Samples taken from the capture rings in one go, the same number of every
+// mic and reference channel at the device rate.
+pub struct CaptureChunk {
+    pub mics: Vec<Vec<f32>>,
+    pub references: Vec<Vec<f32>>,
+}
+
+// One packet of the pipeline output: the mics followed by the beams, and
+// the mics as captured if the pipeline keeps them.
+pub struct ProcessedPacket {
+    pub channels: Vec<Vec<f32>>,
+    pub raw: Vec<Vec<f32>>,
+}
+
+// The stages after the beams, working on all output channels.
+struct TailStages {
+    noise_suppressor: Option<NoiseSuppressor>,
+    noise_block: Vec<Vec<f32>>,
+    gain_control: Option<GainControl>,
+    output: Vec<VecDeque<f32>>,
+}
+
+impl CapturePipeline {
+    pub fn new(
+        cfg: &Config,
+        n_mic: usize,
+        n_ref: usize,
+        beams: Arc<ArcSwap<Vec<BeamConfig>>>,
+        channel_settings: Arc<ArcSwap<Vec<ChannelSettings>>>,
+        keep_raw: bool,
+    ) -> CapturePipeline {
+        let block_len = cfg.processing.block_len.max(1).next_power_of_two();
+        let sample_rate = cfg.mic.stream_rate();
+        let resampler = (cfg.mic.sample_rate != sample_rate)
+            .then(|| Resampler::new(cfg.mic.sample_rate, sample_rate, n_mic + n_ref));
+        let aec = (cfg.aec.enabled && n_ref > 0).then(|| {
+            EchoCanceller::new(&cfg.aec, block_len, sample_rate, n_mic, n_ref)
+        });
+        if cfg.aec.enabled && aec.is_none() {
+            println!("aec: no received channel to use as reference, echo cancellation is off");
+        }
+        let beamformer = (cfg.beamformer.n_beam() > 0 && n_mic > 0).then(|| {
+            Beamformer::new(&cfg.beamformer, block_len, sample_rate, n_mic, beams)
+        });
+        let n_beam = beamformer.as_ref().map_or(0, |beamformer| beamformer.n_beam());
+        let ns = &cfg.noise_suppression;
+        let mut suppressed = vec![false; n_mic + n_beam];
+        if ns.enabled {
+            let channels = ns.mics.iter().map(|&mic| (mic < n_mic).then_some(mic));
+            let beams = ns.beams.iter().map(|&beam| (beam < n_beam).then_some(n_mic + beam));
+            for channel in channels.chain(beams) {
+                match channel {
+                    Some(channel) => suppressed[channel] = true,
+                    None => println!("noise suppression: channel out of range is ignored"),
+                }
+            }
+        }
+        let noise_suppressor = suppressed
+            .contains(&true)
+            .then(|| NoiseSuppressor::new(ns, block_len, sample_rate, &suppressed));
+        CapturePipeline {
+            block_len,
+            resampler,
+            resampled: vec![Vec::new(); n_mic + n_ref],
+            conditioner: Conditioner::new(channel_settings, sample_rate, n_mic),
+            aec,
+            beamformer,
+            tail: TailStages {
+                noise_suppressor,
+                noise_block: vec![vec![0.0; block_len]; n_mic + n_beam],
+                gain_control: GainControl::new(&cfg.agc, sample_rate, n_mic, n_beam),
+                output: vec![VecDeque::new(); n_mic + n_beam],
+            },
+            raw: keep_raw.then(|| vec![VecDeque::new(); n_mic]),
+            input: vec![Vec::new(); n_mic],
+            reference: vec![Vec::new(); n_ref],
+            block: vec![vec![0.0; block_len]; n_mic],
+            ref_block: vec![vec![0.0; block_len]; n_ref],
+            delayed: None,
+            beam_block: vec![vec![0.0; block_len]; n_beam],
+        }
+    }
+
+    pub fn n_beam(&self) -> usize {
+        self.beam_block.len()
+    }
+
+    // Reference channels 'push' expects, i.e. echo reference rings to read.
+    pub fn uses_reference(&self) -> bool {
+        self.aec.is_some()
+    }
+
+    fn is_passthrough(&self) -> bool {
+        self.aec.is_none() && self.beamformer.is_none() && self.tail.noise_suppressor.is_none()
+    }
+
+    // Add 'n' device samples of every mic and reference channel; without
+    // resampling, the mic samples are conditioned in place.
+    pub fn push(&mut self, mics: &mut [Vec<f32>], references: &[Vec<f32>], n: usize) {
+        let Some(resampler) = self.resampler.as_mut() else {
+            self.push_stream(mics, references, n);
+            return;
+        };
+        let mut resampled = std::mem::take(&mut self.resampled);
+        for channel in resampled.iter_mut() {
+            channel.clear();
+        }
+        let input: Vec<&[f32]> = mics.iter().chain(references.iter()).map(|channel| &channel[..n]).collect();
+        resampler.process(&input, &mut resampled);
+        let n = resampled.first().map_or(0, Vec::len);
+        let (mics, references) = resampled.split_at_mut(mics.len());
+        self.push_stream(mics, references, n);
+        self.resampled = resampled;
+    }
+
+    // 'push' at the stream rate.
+    fn push_stream(&mut self, mics: &mut [Vec<f32>], references: &[Vec<f32>], n: usize) {
+        if n == 0 {
+            return;
+        }
+        if let Some(raw) = self.raw.as_mut() {
+            for (raw, mic) in raw.iter_mut().zip(mics.iter()) {
+                raw.extend(mic[..n].iter());
+            }
+        }
+        self.conditioner.process(mics, n);
+        if self.is_passthrough() {
+            let channels: Vec<&[f32]> = mics.iter().map(|mic| &mic[..n]).collect();
+            self.tail.emit(&channels);
+            return;
+        }
+        for (input, mic) in self.input.iter_mut().zip(mics.iter()) {
+            input.extend_from_slice(&mic[..n]);
+        }
+        for (input, reference) in self.reference.iter_mut().zip(references.iter()) {
+            input.extend_from_slice(&reference[..n]);
+        }
+
+        let block_len = self.block_len;
+        while self.input.first().is_some_and(|input| input.len() >= block_len) {
+            for (block, input) in self.block.iter_mut().zip(self.input.iter_mut()) {
+                block.copy_from_slice(&input[..block_len]);
+                input.drain(..block_len);
+            }
+            for (block, input) in self.ref_block.iter_mut().zip(self.reference.iter_mut()) {
+                block.copy_from_slice(&input[..block_len]);
+                input.drain(..block_len);
+            }
+            if let Some(aec) = self.aec.as_mut() {
+                aec.process(&mut self.block, &self.ref_block);
+            }
+            let Some(beamformer) = self.beamformer.as_mut() else {
+                let channels: Vec<&[f32]> = self.block.iter().map(Vec::as_slice).collect();
+                self.tail.emit(&channels);
+                continue;
+            };
+            // the beams lag one block behind, so the mics wait for them; the
+            // very first block only primes the beamformer
+            beamformer.process(&self.block, &mut self.beam_block);
+            match self.delayed.as_mut() {
+                Some(delayed) => {
+                    let channels: Vec<&[f32]> =
+                        delayed.iter().chain(self.beam_block.iter()).map(Vec::as_slice).collect();
+                    self.tail.emit(&channels);
+                    std::mem::swap(delayed, &mut self.block);
+                }
+                None => self.delayed = Some(self.block.clone()),
+            }
+        }
+    }
+
+    // Processed samples ready on every channel.
+    fn available(&self) -> usize {
+        self.tail.output.first().map_or(0, |output| output.len())
+    }
+
+    // Take the next 'n' processed samples of every channel, and as many
+    // unprocessed ones of every mic when the pipeline keeps them.
+    fn pop_packet(&mut self, n: usize) -> ProcessedPacket {
+        ProcessedPacket {
+            channels: self.tail.output.iter_mut().map(|output| output.drain(..n).collect()).collect(),
+            raw: self.raw.iter_mut().flatten().map(|raw| raw.drain(..n).collect()).collect(),
+        }
+    }
+}
+
+// Run 'pipeline' over the chunks of mic and reference samples the sender
+// hands over, and hand back every packet of processed samples. Returns when
+// either side is dropped.
+pub fn run_pipeline(
+    mut pipeline: CapturePipeline,
+    chunks: Receiver<CaptureChunk>,
+    packets: mpsc::Sender<ProcessedPacket>,
+    sample_per_packet: usize,
+) {
+    while let Ok(mut chunk) = chunks.recv() {
+        let n = chunk.mics.iter().chain(chunk.references.iter()).map(Vec::len).min().unwrap_or(0);
+        pipeline.push(&mut chunk.mics, &chunk.references, n);
+        while pipeline.available() >= sample_per_packet {
+            if packets.blocking_send(pipeline.pop_packet(sample_per_packet)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl TailStages {
+    // Pass finished samples of every output channel through the stages that
+    // are enabled to the output.
+    fn emit(&mut self, channels: &[&[f32]]) {
+        let channels: Vec<&[f32]> = match self.noise_suppressor.as_mut() {
+            Some(noise_suppressor) => {
+                if !noise_suppressor.process(channels, &mut self.noise_block) {
+                    return;
+                }
+                self.noise_block.iter().map(Vec::as_slice).collect()
+            }
+            None => channels.to_vec(),
+        };
+        match self.gain_control.as_mut() {
+            Some(gain_control) => gain_control.process(&channels, &mut self.output),
+            None => {
+                for (output, channel) in self.output.iter_mut().zip(channels.iter()) {
+                    output.extend(channel.iter());
+                }
+            }
+        }
+    }
+}
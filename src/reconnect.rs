@@ -0,0 +1,210 @@
+use crate::config_file::ReconnectConfig;
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+
+// Exponential backoff with jitter; the delay grows by 'multiplier' after each
+// failed attempt and is capped at 'max_interval_ms'.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    multiplier: f64,
+    jitter: f64,
+    current: Duration,
+    rng_state: u64,
+}
+
+impl Backoff {
+    pub fn new(cfg: &ReconnectConfig) -> Backoff {
+        let initial = Duration::from_millis(cfg.initial_interval_ms.max(1));
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        Backoff {
+            initial,
+            max: Duration::from_millis(cfg.max_interval_ms).max(initial),
+            multiplier: cfg.multiplier.max(1.0),
+            jitter: cfg.jitter.clamp(0.0, 1.0),
+            current: initial,
+            rng_state: seed | 1,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        // spread the delay uniformly over [1 - jitter, 1 + jitter] of its nominal value
+        let spread = 1.0 + self.jitter * (2.0 * self.next_random() - 1.0);
+        let delay = self.current.mul_f64(spread).min(self.max);
+        self.current = self.current.mul_f64(self.multiplier).min(self.max);
+        delay
+    }
+
+    // xorshift64; good enough to keep many clients from retrying in lockstep
+    fn next_random(&mut self) -> f64 {
+        let mut x = self.rng_state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng_state = x;
+        (x >> 11) as f64 / (1_u64 << 53) as f64
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionState {
+    Connecting { attempt: u64 },
+    Connected,
+    Disconnected,
+    WaitingRetry { delay: Duration, dns_failure: bool },
+    Stopped,
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionState::Connecting { attempt } => write!(f, "connecting (attempt {attempt})"),
+            ConnectionState::Connected => write!(f, "connected"),
+            ConnectionState::Disconnected => write!(f, "disconnected"),
+            ConnectionState::WaitingRetry { delay, dns_failure } => write!(
+                f,
+                "retry in {} ms{}",
+                delay.as_millis(),
+                if *dns_failure { " (host lookup failed)" } else { "" }
+            ),
+            ConnectionState::Stopped => write!(f, "stopped"),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ConnectionMetrics {
+    pub attempts: AtomicU64,
+    pub connects: AtomicU64,
+    pub disconnects: AtomicU64,
+    pub connect_failures: AtomicU64,
+    pub dns_failures: AtomicU64,
+}
+
+impl fmt::Display for ConnectionMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "attempts={} connects={} disconnects={} failures={} dns_failures={}",
+            self.attempts.load(Ordering::Relaxed),
+            self.connects.load(Ordering::Relaxed),
+            self.disconnects.load(Ordering::Relaxed),
+            self.connect_failures.load(Ordering::Relaxed),
+            self.dns_failures.load(Ordering::Relaxed),
+        )
+    }
+}
+
+// Publishes connection state changes of one outbound link; any number of
+// observers can subscribe to the state and read the counters.
+pub struct ConnectionMonitor {
+    name: String,
+    state: watch::Sender<ConnectionState>,
+    pub metrics: Arc<ConnectionMetrics>,
+}
+
+impl ConnectionMonitor {
+    pub fn new(name: &str) -> ConnectionMonitor {
+        let (state, _) = watch::channel(ConnectionState::Disconnected);
+        ConnectionMonitor {
+            name: name.to_string(),
+            state,
+            metrics: Arc::new(ConnectionMetrics::default()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
+
+    pub fn set(&self, state: ConnectionState) {
+        let metrics = &self.metrics;
+        match state {
+            ConnectionState::Connecting { .. } => {
+                metrics.attempts.fetch_add(1, Ordering::Relaxed);
+            }
+            ConnectionState::Connected => {
+                metrics.connects.fetch_add(1, Ordering::Relaxed);
+            }
+            ConnectionState::Disconnected => {
+                metrics.disconnects.fetch_add(1, Ordering::Relaxed);
+            }
+            ConnectionState::WaitingRetry { .. } | ConnectionState::Stopped => {}
+        }
+        self.state.send_replace(state);
+    }
+
+    pub fn connect_failed(&self, dns_failure: bool) {
+        if dns_failure {
+            self.metrics.dns_failures.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.metrics.connect_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Print every state change of a monitored connection together with its counters.
+pub async fn log_connection_events(
+    name: String,
+    mut state: watch::Receiver<ConnectionState>,
+    metrics: Arc<ConnectionMetrics>,
+) {
+    while state.changed().await.is_ok() {
+        let current = *state.borrow();
+        println!("{}: {} [{}]", name, current, metrics);
+        if current == ConnectionState::Stopped {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(jitter: f64) -> ReconnectConfig {
+        ReconnectConfig {
+            initial_interval_ms: 100,
+            max_interval_ms: 1000,
+            multiplier: 2.0,
+            jitter,
+            retry_on_dns_failure: false,
+        }
+    }
+
+    #[test]
+    fn delay_grows_to_the_cap() {
+        let mut backoff = Backoff::new(&config(0.0));
+        let delays: Vec<u64> = (0..6).map(|_| backoff.next_delay().as_millis() as u64).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn jitter_stays_in_bounds() {
+        let mut backoff = Backoff::new(&config(0.5));
+        let mut nominal = 100.0_f64;
+        for _ in 0..1000 {
+            let delay = backoff.next_delay().as_secs_f64() * 1000.0;
+            assert!(delay >= nominal * 0.5 - 1e-6, "{delay} below {nominal}");
+            assert!(delay <= (nominal * 1.5).min(1000.0) + 1e-6, "{delay} above {nominal}");
+            nominal = (nominal * 2.0).min(1000.0);
+        }
+    }
+}
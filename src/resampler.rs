@@ -0,0 +1,180 @@
+use std::f64::consts::PI;
+
+// zero crossings of the sinc on each side, at the lower of the two rates
+const ZERO_CROSSINGS: usize = 24;
+// cutoff relative to the lower Nyquist frequency
+const CUTOFF: f64 = 0.9;
+// Kaiser window, about 70 dB stop band attenuation
+const KAISER_BETA: f64 = 7.0;
+
+// Rational resampler by 'up' / 'down', a Kaiser windowed sinc split into
+// 'up' polyphase filters. Output sample n sits at input time n * down / up:
+// the first outputs wait for half a filter of input rather than starting
+// with the filter delay, so every input sample maps to a fixed output time
+// and sample counters convert exactly between the two rates.
+pub struct Resampler {
+    up: usize,
+    down: usize,
+    taps: usize,
+    // coefficients of phase p at p * taps.., newest input sample first
+    coeffs: Vec<f32>,
+    // per channel: past taps - 1 samples and the input not used yet
+    history: Vec<Vec<f32>>,
+    // index in 'history' of the newest input sample of the next output
+    position: usize,
+    phase: usize,
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+// modified Bessel function of the first kind, order 0
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    for k in 1..50 {
+        term *= (x / 2.0) / k as f64;
+        sum += term * term;
+        if term * term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+impl Resampler {
+    pub fn new(from_rate: usize, to_rate: usize, n_channel: usize) -> Resampler {
+        let divisor = gcd(from_rate, to_rate).max(1);
+        let (up, down) = (to_rate / divisor, from_rate / divisor);
+        let (up, down) = (up.max(1), down.max(1));
+        let slower = up.max(down) as f64;
+        let taps = (2.0 * ZERO_CROSSINGS as f64 * slower / (CUTOFF * up as f64)).ceil() as usize;
+        // the prototype runs at the input rate times 'up'
+        let len = taps * up;
+        let fc = CUTOFF / (2.0 * slower);
+        // centered on input sample taps / 2 of phase 0, which 'position'
+        // starts ahead by
+        let center = (taps / 2 * up) as f64;
+        let norm = bessel_i0(KAISER_BETA);
+        let mut coeffs = vec![0.0; len];
+        for phase in 0..up {
+            for k in 0..taps {
+                let j = phase + k * up;
+                let t = j as f64 - center;
+                let sinc = if t == 0.0 { 2.0 * fc } else { (2.0 * PI * fc * t).sin() / (PI * t) };
+                let w = 1.0 - (t / center.max(1.0)).powi(2);
+                let window = bessel_i0(KAISER_BETA * w.max(0.0).sqrt()) / norm;
+                coeffs[phase * taps + k] = (up as f64 * sinc * window) as f32;
+            }
+        }
+        Resampler {
+            up,
+            down,
+            taps,
+            coeffs,
+            history: vec![vec![0.0; taps - 1]; n_channel],
+            position: taps - 1 + taps / 2,
+            phase: 0,
+        }
+    }
+
+    // Most output samples 'n_input' input samples can make.
+    pub fn max_output(&self, n_input: usize) -> usize {
+        (n_input * self.up).div_ceil(self.down) + 1
+    }
+
+    // Append the resampled chunk of every channel to 'output'. Channels all
+    // get the same number of input samples, so they stay in step.
+    pub fn process(&mut self, input: &[&[f32]], output: &mut [Vec<f32>]) {
+        let start = (self.position, self.phase);
+        for ((history, input), output) in self.history.iter_mut().zip(input.iter()).zip(output.iter_mut()) {
+            history.extend_from_slice(input);
+            let (mut position, mut phase) = start;
+            while position < history.len() {
+                let coeffs = &self.coeffs[phase * self.taps..(phase + 1) * self.taps];
+                let samples = history[position + 1 - self.taps..=position].iter().rev();
+                output.push(coeffs.iter().zip(samples).map(|(&h, &x)| h * x).sum());
+                phase += self.down;
+                position += phase / self.up;
+                phase %= self.up;
+            }
+            // keep what the next outputs reach back to; a filter is longer
+            // than one step, so that never goes past the end
+            let used = position + 1 - self.taps;
+            history.drain(..used);
+            self.position = position - used;
+            self.phase = phase;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resample(from_rate: usize, to_rate: usize, input: &[f32], chunk: usize) -> Vec<f32> {
+        let mut resampler = Resampler::new(from_rate, to_rate, 1);
+        let mut output = vec![Vec::new()];
+        for input in input.chunks(chunk) {
+            let before = output[0].len();
+            resampler.process(&[input], &mut output);
+            assert!(output[0].len() - before <= resampler.max_output(input.len()));
+        }
+        output.pop().unwrap()
+    }
+
+    fn sine(hz: f64, rate: usize, len: usize) -> Vec<f32> {
+        (0..len).map(|i| (2.0 * PI * hz * i as f64 / rate as f64).sin() as f32).collect()
+    }
+
+    #[test]
+    fn output_length() {
+        let input = vec![0.5; 48000];
+        let taps = Resampler::new(48000, 16000, 1).taps;
+        // output n needs input up to 3 n + taps / 2
+        let output = resample(48000, 16000, &input, 480);
+        assert_eq!(output.len(), (48000 - taps / 2).div_ceil(3));
+        // the chunk size does not matter
+        assert_eq!(output, resample(48000, 16000, &input, 77));
+    }
+
+    #[test]
+    fn first_input_at_first_output() {
+        for (from_rate, to_rate) in [(48000, 16000), (16000, 48000), (44100, 16000)] {
+            let mut input = vec![0.0; from_rate / 10];
+            input[0] = 1.0;
+            let output = resample(from_rate, to_rate, &input, 256);
+            let peak = (0..output.len()).max_by(|&a, &b| output[a].total_cmp(&output[b])).unwrap();
+            assert_eq!(peak, 0, "{from_rate} -> {to_rate}");
+        }
+    }
+
+    #[test]
+    fn passes_dc_and_sine() {
+        for (from_rate, to_rate) in [(48000, 16000), (16000, 48000), (44100, 16000)] {
+            let output = resample(from_rate, to_rate, &vec![1.0; from_rate], 512);
+            // skip the start, where the filter still reaches back before the input
+            for &s in output[to_rate / 10..].iter() {
+                assert!((s - 1.0).abs() < 1e-3, "{from_rate} -> {to_rate}: dc {s}");
+            }
+            let output = resample(from_rate, to_rate, &sine(1000.0, from_rate, from_rate), 512);
+            for (n, &s) in output.iter().enumerate().skip(to_rate / 10) {
+                // output n sits at time n / to_rate, like input n at n / from_rate
+                let expected = (2.0 * PI * 1000.0 * n as f64 / to_rate as f64).sin() as f32;
+                assert!((s - expected).abs() < 1e-3, "{from_rate} -> {to_rate}: sample {n} {s} {expected}");
+            }
+        }
+    }
+
+    #[test]
+    fn stops_above_nyquist() {
+        let output = resample(48000, 16000, &sine(12000.0, 48000, 48000), 512);
+        let peak = output[1600..].iter().fold(0.0_f32, |peak, s| peak.max(s.abs()));
+        assert!(peak < 1e-3, "alias {peak}");
+    }
+}
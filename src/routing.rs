@@ -0,0 +1,155 @@
+use crate::config_file::{Config, Route, RouteSource};
+use std::fmt;
+
+// Routes from mic and received channels to speaker outputs, checked against
+// the channel counts so the audio callback can index without bounds errors.
+// Replaced as a whole (through ArcSwap) when changed at runtime.
+#[derive(Clone, Debug)]
+pub struct RoutingMatrix {
+    routes: Vec<Route>,
+    n_mic: usize,
+    n_recv: usize,
+    n_output: usize,
+}
+
+impl RoutingMatrix {
+    pub fn from_config(cfg: &Config) -> RoutingMatrix {
+        let mut matrix = RoutingMatrix {
+            routes: Vec::new(),
+            n_mic: cfg.mic.total_channels(),
+            n_recv: cfg.tcp_receiver.n_channel,
+            n_output: cfg.speaker.n_channel,
+        };
+        let routes = if cfg.routing.routes.is_empty() {
+            let mut routes: Vec<Route> = (0..matrix.n_output.min(matrix.n_recv))
+                .map(|i| Route {
+                    source: RouteSource::Recv,
+                    channel: i,
+                    output: i,
+                    gain: 1.0,
+                })
+                .collect();
+            if cfg.audio_connection.connect_mic_speaker {
+                routes.push(Route {
+                    source: RouteSource::Mic,
+                    channel: cfg.audio_connection.mic_idx,
+                    output: cfg.audio_connection.speaker_idx,
+                    gain: 1.0,
+                });
+            }
+            routes
+        } else {
+            cfg.routing.routes.clone()
+        };
+        for route in routes {
+            if let Err(err) = matrix.set(route) {
+                println!("routing: ignore route; {}", err);
+            }
+        }
+        matrix
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    // Add a route, or change the gain of an existing one.
+    pub fn set(&mut self, route: Route) -> crate::Result<()> {
+        let n_source = match route.source {
+            RouteSource::Mic => self.n_mic,
+            RouteSource::Recv => self.n_recv,
+        };
+        if route.channel >= n_source {
+            return Err(format!("{:?} channel {} out of range (0..{})", route.source, route.channel, n_source).into());
+        }
+        if route.output >= self.n_output {
+            return Err(format!("output {} out of range (0..{})", route.output, self.n_output).into());
+        }
+        if !route.gain.is_finite() {
+            return Err(format!("invalid gain {}", route.gain).into());
+        }
+        match self.routes.iter_mut().find(|r| {
+            (r.source, r.channel, r.output) == (route.source, route.channel, route.output)
+        }) {
+            Some(existing) => existing.gain = route.gain,
+            None => self.routes.push(route),
+        }
+        Ok(())
+    }
+
+    // Returns false if there was no such route.
+    pub fn remove(&mut self, source: RouteSource, channel: usize, output: usize) -> bool {
+        let n_routes = self.routes.len();
+        self.routes
+            .retain(|r| (r.source, r.channel, r.output) != (source, channel, output));
+        self.routes.len() != n_routes
+    }
+
+    pub fn clear(&mut self) {
+        self.routes.clear();
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let source = match self.source {
+            RouteSource::Mic => "mic",
+            RouteSource::Recv => "recv",
+        };
+        write!(f, "{} {} -> {} gain {}", source, self.channel, self.output, self.gain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix() -> RoutingMatrix {
+        RoutingMatrix {
+            routes: Vec::new(),
+            n_mic: 4,
+            n_recv: 2,
+            n_output: 2,
+        }
+    }
+
+    fn route(source: RouteSource, channel: usize, output: usize, gain: f32) -> Route {
+        Route { source, channel, output, gain }
+    }
+
+    #[test]
+    fn rejects_routes_out_of_range() {
+        let mut matrix = matrix();
+        assert!(matrix.set(route(RouteSource::Mic, 3, 1, 1.0)).is_ok());
+        assert!(matrix.set(route(RouteSource::Recv, 2, 0, 1.0)).is_err());
+        assert!(matrix.set(route(RouteSource::Mic, 4, 0, 1.0)).is_err());
+        assert!(matrix.set(route(RouteSource::Mic, 0, 2, 1.0)).is_err());
+        assert!(matrix.set(route(RouteSource::Mic, 0, 0, f32::NAN)).is_err());
+        assert_eq!(matrix.routes(), [route(RouteSource::Mic, 3, 1, 1.0)]);
+    }
+
+    #[test]
+    fn set_changes_gain_of_existing_route() {
+        let mut matrix = matrix();
+        matrix.set(route(RouteSource::Recv, 0, 0, 1.0)).unwrap();
+        matrix.set(route(RouteSource::Mic, 0, 0, 1.0)).unwrap();
+        matrix.set(route(RouteSource::Recv, 0, 0, 0.5)).unwrap();
+        assert_eq!(
+            matrix.routes(),
+            [route(RouteSource::Recv, 0, 0, 0.5), route(RouteSource::Mic, 0, 0, 1.0)]
+        );
+    }
+
+    #[test]
+    fn removes_only_the_given_route() {
+        let mut matrix = matrix();
+        matrix.set(route(RouteSource::Recv, 0, 0, 1.0)).unwrap();
+        matrix.set(route(RouteSource::Recv, 0, 1, 1.0)).unwrap();
+        assert!(matrix.remove(RouteSource::Recv, 0, 1));
+        assert!(!matrix.remove(RouteSource::Recv, 0, 1));
+        assert!(!matrix.remove(RouteSource::Mic, 0, 0));
+        assert_eq!(matrix.routes(), [route(RouteSource::Recv, 0, 0, 1.0)]);
+        matrix.clear();
+        assert!(matrix.routes().is_empty());
+    }
+}
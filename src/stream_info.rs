@@ -0,0 +1,202 @@
+use crate::config_file::{Config, SampleFormat};
+use serde::Serialize;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::time::{self, Duration};
+
+// Layout of the packets sent by tcp_sender, for clients that need to know
+// what each channel carries.
+#[derive(Serialize)]
+pub struct StreamInfo {
+    pub device_id: u16,
+    pub sample_rate: usize,
+    pub sample_per_packet: usize,
+    pub sample_format: SampleFormat,
+    pub header_len: usize,
+    pub packet_len: usize,
+    // capture devices and the channels they fill, in stream order
+    pub devices: Vec<DeviceInfo>,
+    pub channels: Vec<ChannelInfo>,
+    // beams served on a port of their own
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub beam_stream: Option<BeamStreamInfo>,
+}
+
+#[derive(Serialize)]
+pub struct BeamStreamInfo {
+    pub port: usize,
+    pub packet_len: usize,
+    pub channels: Vec<ChannelInfo>,
+}
+
+#[derive(Serialize)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub device_name: String,
+    pub first_channel: usize,
+    pub n_channel: usize,
+}
+
+#[derive(Serialize)]
+pub struct ChannelInfo {
+    pub index: usize,
+    pub name: String,
+    pub kind: ChannelKind,
+    // JACK port the channel is captured from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<String>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelKind {
+    Mic,
+    // a received channel sent back as echo reference
+    Reference,
+    // a beam over the mic array
+    Beam,
+}
+
+impl DeviceInfo {
+    fn contains(&self, channel: usize) -> bool {
+        (self.first_channel..self.first_channel + self.n_channel).contains(&channel)
+    }
+}
+
+impl StreamInfo {
+    // 'capture_ports' as resolved by jack_client::capture_ports, 'n_beam'
+    // as formed by the capture pipeline.
+    pub fn new(cfg: &Config, capture_ports: &[Option<String>], n_beam: usize) -> StreamInfo {
+        let mut devices = Vec::new();
+        // mapped channels may come from anywhere, only the bridged devices
+        // are known to be whole
+        if cfg.mic.channels.is_empty() {
+            devices.push(DeviceInfo {
+                name: "main".to_string(),
+                device_name: cfg.mic.device_name.clone(),
+                first_channel: 0,
+                n_channel: cfg.mic.n_channel,
+            });
+        }
+        let mut first_channel = cfg.mic.n_channel;
+        for device in cfg.mic.extra_devices.iter() {
+            devices.push(DeviceInfo {
+                name: device.name.clone(),
+                device_name: device.device_name.clone(),
+                first_channel,
+                n_channel: device.n_channel,
+            });
+            first_channel += device.n_channel;
+        }
+
+        let mut channels = Vec::new();
+        for i in 0..cfg.mic.total_channels() {
+            let bridged = devices.iter().find(|d| d.first_channel >= cfg.mic.n_channel && d.contains(i));
+            channels.push(ChannelInfo {
+                index: channels.len(),
+                name: match (cfg.mic.channels.get(i), bridged) {
+                    (Some(mapping), _) => mapping.name.clone(),
+                    (None, Some(device)) => format!("{}_{}", device.name, i - device.first_channel),
+                    (None, None) => format!("mic_{i}"),
+                },
+                kind: ChannelKind::Mic,
+                port: capture_ports.get(i).cloned().flatten(),
+            });
+        }
+        for i in 0..cfg.speaker.n_channel {
+            channels.push(ChannelInfo {
+                index: channels.len(),
+                name: cfg
+                    .speaker
+                    .channels
+                    .get(i)
+                    .map_or_else(|| format!("ref_{i}"), |mapping| format!("{}_ref", mapping.name)),
+                kind: ChannelKind::Reference,
+                port: None,
+            });
+        }
+        let beams = cfg.beamformer.beams.iter().take(n_beam);
+        let sample_per_packet = cfg.tcp_sender.sample_per_packet;
+        let channel_len = sample_per_packet * cfg.tcp_sender.sample_format.bytes();
+        let mut beam_stream = None;
+        if n_beam > 0 && cfg.beamformer.listen_port == 0 {
+            for beam in beams {
+                channels.push(ChannelInfo {
+                    index: channels.len(),
+                    name: beam.name.clone(),
+                    kind: ChannelKind::Beam,
+                    port: None,
+                });
+            }
+        } else if n_beam > 0 {
+            let channels: Vec<ChannelInfo> = beams
+                .enumerate()
+                .map(|(index, beam)| ChannelInfo {
+                    index,
+                    name: beam.name.clone(),
+                    kind: ChannelKind::Beam,
+                    port: None,
+                })
+                .collect();
+            beam_stream = Some(BeamStreamInfo {
+                port: cfg.beamformer.listen_port,
+                packet_len: cfg.tcp_sender.header_len + channel_len * channels.len(),
+                channels,
+            });
+        }
+        StreamInfo {
+            device_id: cfg.mic.device_id as u16,
+            sample_rate: cfg.mic.stream_rate(),
+            sample_per_packet,
+            sample_format: cfg.tcp_sender.sample_format,
+            header_len: cfg.tcp_sender.header_len,
+            packet_len: cfg.tcp_sender.header_len + channel_len * channels.len(),
+            devices,
+            channels,
+            beam_stream,
+        }
+    }
+}
+
+// Send the stream description as TOML to every client that connects, then
+// close the connection.
+pub async fn serve_stream_info(port: usize, info: &StreamInfo, shutdown: impl Future) {
+    let text: Arc<str> = match toml::to_string(info) {
+        Ok(text) => text.into(),
+        Err(err) => {
+            println!("Error! Failed to encode stream info. {}", err);
+            return;
+        }
+    };
+    let listener = match TcpListener::bind(format!("0.0.0.0:{}", port)).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            println!("Error! Failed to listen on stream info port {}. {}", port, err);
+            return;
+        }
+    };
+    println!("stream info on port: {}", port);
+
+    tokio::select! {
+        _ = async {
+            loop {
+                match listener.accept().await {
+                    Ok((mut socket, _)) => {
+                        let text = text.clone();
+                        tokio::spawn(async move {
+                            let _ = socket.write_all(text.as_bytes()).await;
+                            let _ = socket.shutdown().await;
+                        });
+                    }
+                    Err(err) => {
+                        println!("Error! Failed to accept stream info connection. {}", err);
+                        time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        } => {}
+        _ = shutdown => {}
+    }
+}
@@ -1,14 +1,93 @@
-use crate::config_file::Config;
-use std::sync::Arc;
+use crate::config_file::{BridgeKind, Config, ExtraDevice};
+use crate::hotplug::{alsa_card, card_present};
+use crate::jack_client::open_client;
+use std::collections::VecDeque;
+use std::fmt;
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::{Child, Command};
+use tokio::task::{self, JoinHandle};
+use tokio::time::{sleep, timeout, Duration, Instant};
 
-#[inline(always)]
-pub fn start_jackd(conf: Arc<Config>) -> Child {
-    let mut jack_server = Command::new("jackd");
-    jack_server.kill_on_drop(true);
-    if !conf.mic.start_jackd {
-        return jack_server.spawn().unwrap();
+// lines of jackd's stderr kept to explain why it exited
+const STDERR_TAIL_LINES: usize = 20;
+
+// The jackd process we started, or nothing if mic.start_jackd is off and an
+// externally managed server is used. Its stderr is forwarded to our log.
+pub struct JackServer {
+    child: Option<Child>,
+    stderr_tail: Arc<Mutex<VecDeque<String>>>,
+    stderr_task: Option<JoinHandle<()>>,
+}
+
+impl JackServer {
+    // Needs to run inside the tokio runtime.
+    pub fn start(conf: Arc<Config>) -> std::io::Result<JackServer> {
+        let mut server = JackServer {
+            child: None,
+            stderr_tail: Arc::new(Mutex::new(VecDeque::new())),
+            stderr_task: None,
+        };
+        if !conf.mic.start_jackd {
+            return Ok(server);
+        }
+        let mut child = jackd_command(&conf).spawn()?;
+        if let Some(stderr) = child.stderr.take() {
+            let tail = server.stderr_tail.clone();
+            server.stderr_task = Some(tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    println!("jackd: {}", line);
+                    let mut tail = tail.lock().unwrap();
+                    if tail.len() == STDERR_TAIL_LINES {
+                        tail.pop_front();
+                    }
+                    tail.push_back(line);
+                }
+            }));
+        }
+        server.child = Some(child);
+        Ok(server)
+    }
+
+    // Exit status once the jackd we started has exited.
+    pub fn try_wait(&mut self) -> Option<ExitStatus> {
+        self.child.as_mut()?.try_wait().ok().flatten()
+    }
+
+    // Kill the jackd we started, if it is still running, and wait up to
+    // 'wait' for it to exit so a new one does not race it for the device
+    // and the server socket.
+    pub async fn stop(&mut self, wait: Duration) {
+        let Some(child) = self.child.as_mut() else {
+            return;
+        };
+        let _ = child.start_kill();
+        match timeout(wait, child.wait()).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => println!("Error! Failed to wait for jackd to exit. {}", err),
+            Err(_) => println!("jackd did not exit within {:?}", wait),
+        }
+    }
+
+    // Read what is left of stderr after jackd exited and explain the exit.
+    async fn exit_error(&mut self, status: ExitStatus) -> JackdError {
+        if let Some(task) = self.stderr_task.take() {
+            let _ = timeout(Duration::from_millis(500), task).await;
+        }
+        let stderr: Vec<String> = self.stderr_tail.lock().unwrap().iter().cloned().collect();
+        JackdError::Exited {
+            status,
+            cause: JackdFailure::classify(&stderr),
+            stderr,
+        }
     }
+}
+
+fn jackd_command(conf: &Config) -> Command {
+    let mut jack_server = Command::new("jackd");
+    jack_server.kill_on_drop(true).stderr(Stdio::piped());
     if conf.mic.driver.to_lowercase().contains("coreaudio") {
         jack_server
             .arg("-R")
@@ -24,7 +103,175 @@ pub fn start_jackd(conf: Arc<Config>) -> Child {
             .arg(format!("-n{}", conf.mic.n_period))
             .arg(format!("-r{}", conf.mic.sample_rate));
     }
-    jack_server.spawn().unwrap()
+    jack_server
+}
+
+// Likely reason jackd gave up, guessed from its stderr.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JackdFailure {
+    BadDevice,
+    DeviceBusy,
+    UnsupportedRate,
+    Unknown,
+}
+
+impl JackdFailure {
+    fn classify(stderr: &[String]) -> JackdFailure {
+        let text = stderr.join("\n").to_lowercase();
+        if text.contains("busy") || text.contains("already in use") {
+            JackdFailure::DeviceBusy
+        } else if text.contains("sample rate") || text.contains("sample/frame rate") {
+            JackdFailure::UnsupportedRate
+        } else if text.contains("card index")
+            || text.contains("cannot find card")
+            || text.contains("no such")
+            || text.contains("cannot open")
+        {
+            JackdFailure::BadDevice
+        } else {
+            JackdFailure::Unknown
+        }
+    }
+}
+
+impl fmt::Display for JackdFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JackdFailure::BadDevice => write!(f, "audio device not found, check mic.device_name and speaker.device_name"),
+            JackdFailure::DeviceBusy => write!(f, "audio device is busy, another program is using it"),
+            JackdFailure::UnsupportedRate => write!(f, "audio device does not support mic.sample_rate"),
+            JackdFailure::Unknown => write!(f, "see jackd output above"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum JackdError {
+    Exited {
+        status: ExitStatus,
+        cause: JackdFailure,
+        stderr: Vec<String>,
+    },
+    Timeout {
+        waited: Duration,
+        last_error: jack::Error,
+    },
+}
+
+impl fmt::Display for JackdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JackdError::Exited { status, cause, stderr } => {
+                write!(f, "jackd exited with {}: {}", status, cause)?;
+                if let Some(line) = stderr.last() {
+                    write!(f, " (last output: {})", line)?;
+                }
+                Ok(())
+            }
+            JackdError::Timeout { waited, last_error } => write!(
+                f,
+                "JACK server not ready after {} ms: {}",
+                waited.as_millis(),
+                last_error
+            ),
+        }
+    }
+}
+
+impl std::error::Error for JackdError {}
+
+// Poll until the server accepts a client, jackd exits or 'wait' runs out.
+pub async fn wait_for_jackd(
+    server: &mut JackServer,
+    wait: Duration,
+) -> Result<jack::Client, JackdError> {
+    let started = Instant::now();
+    loop {
+        // opening a client blocks while it talks to the server
+        let last_error = match task::spawn_blocking(open_client).await.unwrap() {
+            Ok(client) => {
+                println!("JACK server ready after {} ms", started.elapsed().as_millis());
+                return Ok(client);
+            }
+            Err(err) => err,
+        };
+        if let Some(status) = server.try_wait() {
+            return Err(server.exit_error(status).await);
+        }
+        if started.elapsed() >= wait {
+            return Err(JackdError::Timeout {
+                waited: started.elapsed(),
+                last_error,
+            });
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+// zita-a2j or alsa_in processes bridging the extra capture devices into
+// JACK; they resample to the JACK clock, which keeps the devices aligned.
+pub struct CaptureBridges {
+    bridges: Vec<(ExtraDevice, Option<Child>)>,
+}
+
+impl CaptureBridges {
+    pub fn new(conf: &Config) -> CaptureBridges {
+        CaptureBridges {
+            bridges: conf
+                .mic
+                .extra_devices
+                .iter()
+                .map(|device| (device.clone(), None))
+                .collect(),
+        }
+    }
+
+    // Start bridges that are not running, e.g. because the JACK server
+    // restarted. Needs to run inside the tokio runtime.
+    pub fn ensure_running(&mut self, conf: &Config) {
+        for (device, child) in self.bridges.iter_mut() {
+            if let Some(running) = child.as_mut() {
+                match running.try_wait() {
+                    Ok(None) => continue,
+                    Ok(Some(status)) => println!("{} exited with {}", device.jack_name(), status),
+                    Err(err) => println!("{} lost. {}", device.jack_name(), err),
+                }
+                *child = None;
+            }
+            if alsa_card(&device.device_name).is_some_and(|card| !card_present(&card)) {
+                continue;
+            }
+            match bridge_command(conf, device).spawn() {
+                Ok(started) => {
+                    println!("started {} for {}", device.jack_name(), device.device_name);
+                    *child = Some(started);
+                }
+                Err(err) => println!("failed to start bridge for {}! {}", device.device_name, err),
+            }
+        }
+    }
+}
+
+fn bridge_command(conf: &Config, device: &ExtraDevice) -> Command {
+    let mut bridge = Command::new(match device.bridge {
+        BridgeKind::Zita => "zita-a2j",
+        BridgeKind::AlsaIn => "alsa_in",
+    });
+    bridge
+        .kill_on_drop(true)
+        .arg("-j")
+        .arg(device.jack_name())
+        .arg("-d")
+        .arg(&device.device_name)
+        .arg("-r")
+        .arg(conf.mic.sample_rate.to_string())
+        .arg("-p")
+        .arg(conf.mic.period.to_string())
+        .arg("-n")
+        .arg(conf.mic.n_period.to_string())
+        .arg("-c")
+        .arg(device.n_channel.to_string());
+    bridge
 }
 
 #[inline(always)]
@@ -1,13 +1,16 @@
 use crate::Config;
+use crate::config_file::ReconnectConfig;
 
 use std::future::Future;
+use std::io::ErrorKind;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 // use crossbeam::channel::Sender;
 use tokio::sync::mpsc::Sender;
 use tokio::io::AsyncReadExt;
-use tokio::time::{self, Duration};
+use tokio::time;
 use tokio::net::{self, TcpStream};
+use crate::reconnect::{Backoff, ConnectionMonitor, ConnectionState};
 
 
 pub struct TcpClient {
@@ -16,28 +19,64 @@ pub struct TcpClient {
     pkt_size: usize,
     resend: Sender<Vec<u8>>,
     shutdown: AtomicBool,
+    backoff: Backoff,
+    retry_on_dns_failure: bool,
+    monitor: Arc<ConnectionMonitor>,
 }
 
 impl TcpClient {
+    pub fn new(
+        host: String,
+        port: usize,
+        pkt_size: usize,
+        resend: Sender<Vec<u8>>,
+        reconnect: &ReconnectConfig,
+        monitor: Arc<ConnectionMonitor>,
+    ) -> TcpClient {
+        TcpClient {
+            host,
+            port,
+            pkt_size,
+            resend,
+            shutdown: AtomicBool::new(false),
+            backoff: Backoff::new(reconnect),
+            retry_on_dns_failure: reconnect.retry_on_dns_failure,
+            monitor,
+        }
+    }
+
     pub async fn inf_run(&mut self) -> crate::Result<()> {
-        while self.shutdown.load(Ordering::Relaxed) != true {
-            let addr = format!("{}:{}", self.host, self.port);
-            match TcpStream::connect(&addr).await {
+        let addr = format!("{}:{}", self.host, self.port);
+        let mut attempt = 0_u64;
+        while !self.shutdown.load(Ordering::Relaxed) {
+            attempt += 1;
+            self.monitor.set(ConnectionState::Connecting { attempt });
+            let dns_failure = match TcpStream::connect(&addr).await {
                 Ok(tcp_stream) => {
                     println!("Connected to {}", self.host);
+                    self.monitor.set(ConnectionState::Connected);
+                    self.backoff.reset();
+                    attempt = 0;
                     self.inner_loop(tcp_stream,).await;
                     println!("Disconnected from {}", self.host);
+                    self.monitor.set(ConnectionState::Disconnected);
+                    false
                 }
                 Err(_) => {
-                    if let Err(_) = net::lookup_host(&addr).await {
+                    let dns_failure = net::lookup_host(&addr).await.is_err();
+                    self.monitor.connect_failed(dns_failure);
+                    if dns_failure && !self.retry_on_dns_failure {
+                        self.monitor.set(ConnectionState::Stopped);
                         return Ok(());
-                    } else {
-                        time::sleep(Duration::from_secs(2)).await;
-                        println!("Try to reconnect");
                     }
+                    dns_failure
                 }
-            }
+            };
+            let delay = self.backoff.next_delay();
+            self.monitor.set(ConnectionState::WaitingRetry { delay, dns_failure });
+            time::sleep(delay).await;
         }
+        self.monitor.set(ConnectionState::Stopped);
         Ok(())
     }
     
@@ -45,56 +84,65 @@ impl TcpClient {
         &mut self,
         mut tcp_stream: TcpStream,
     ) {
-        let mut pkt_buf = Vec::<u8>::with_capacity(self.pkt_size);
-        while self.shutdown.load(Ordering::Relaxed) != true {
-            match tcp_stream.read_buf(&mut pkt_buf).await {
-                Ok(0) => break,
-                Ok(_) => {
-                    if pkt_buf.len() < self.pkt_size {
-                        continue;
-                    }
-                    assert_eq!(pkt_buf.len(), self.pkt_size);
-                    let resend_buf = pkt_buf.clone();
-                    let _ = self.resend.send(resend_buf).await;
+        forward_packets(&mut tcp_stream, self.pkt_size, &self.resend).await;
+        println!("inner loop finished");
+        drop(tcp_stream);
+    }
+}
 
-                    pkt_buf.clear();
-                }
-                Err(_) => {
-                    println!("TCP client read data error");
-                    return
-                }
+// Read fixed-size packets from 'tcp_stream' and pass them on until the peer disconnects.
+pub(crate) async fn forward_packets(
+    tcp_stream: &mut TcpStream,
+    pkt_size: usize,
+    resend: &Sender<Vec<u8>>,
+) {
+    let mut pkt_buf = vec![0_u8; pkt_size];
+    loop {
+        if let Err(err) = tcp_stream.read_exact(&mut pkt_buf).await {
+            if err.kind() != ErrorKind::UnexpectedEof {
+                println!("TCP client read data error");
             }
+            return;
+        }
+        if resend.send(pkt_buf.clone()).await.is_err() {
+            return;
         }
-        println!("inner loop finished");
-        drop(tcp_stream);
     }
 }
 
 pub(crate) async fn start_tcp_client(
     cfg: Arc<Config>,
     resend: Sender<Vec<u8>>,
+    monitor: Arc<ConnectionMonitor>,
     shutdown: impl Future,
 ) {
     let (host, port) = (cfg.tcp_receiver.host.clone(), cfg.tcp_receiver.port);
+    if host == "none" {
+        monitor.set(ConnectionState::Stopped);
+        return;
+    }
     let pkt_size = cfg.tcp_receiver.header_len + 
-        cfg.tcp_receiver.n_channel * cfg.tcp_receiver.sample_per_packet * 2;
+        cfg.tcp_receiver.n_channel * cfg.tcp_receiver.sample_per_packet * cfg.tcp_receiver.sample_format.bytes();
 
-    let mut client = TcpClient{
-        host, 
-        port, 
+    let mut client = TcpClient::new(
+        host,
+        port,
         pkt_size,
         resend,
-        shutdown: AtomicBool::new(false)};
+        &cfg.tcp_receiver.reconnect,
+        monitor,
+    );
     tokio::select! {
         res = client.inf_run() => {
-            if let Err(_) = res {
+            if res.is_err() {
                 println!("Failed to start tcp client");
             }
         }
         _ = shutdown => {
             client.shutdown.store(true, Ordering::Relaxed);
+            client.monitor.set(ConnectionState::Stopped);
             println!("Try to disconnect");
             drop(client);
         }
     }
-}
\ No newline at end of file
+}
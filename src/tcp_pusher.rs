@@ -0,0 +1,113 @@
+use crate::config_file::ReconnectConfig;
+use crate::reconnect::{log_connection_events, Backoff, ConnectionMonitor, ConnectionState};
+
+use std::future::Future;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{self, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinSet;
+use tokio::time;
+
+// Dials out to a collector and pushes the same packet stream the tcp server
+// serves to its clients; used when the collector cannot reach us (e.g. NAT).
+pub struct TcpPusher {
+    addr: String,
+    pkt_sender: broadcast::Sender<Vec<u8>>,
+    backoff: Backoff,
+    retry_on_dns_failure: bool,
+    monitor: Arc<ConnectionMonitor>,
+}
+
+impl TcpPusher {
+    pub async fn inf_run(&mut self) {
+        let mut attempt = 0_u64;
+        loop {
+            attempt += 1;
+            self.monitor.set(ConnectionState::Connecting { attempt });
+            let dns_failure = match TcpStream::connect(&self.addr).await {
+                Ok(tcp_stream) => {
+                    println!("Pushing stream to {}", self.addr);
+                    self.monitor.set(ConnectionState::Connected);
+                    self.backoff.reset();
+                    attempt = 0;
+                    self.push_loop(tcp_stream).await;
+                    println!("Stopped pushing to {}", self.addr);
+                    self.monitor.set(ConnectionState::Disconnected);
+                    false
+                }
+                Err(_) => {
+                    let dns_failure = net::lookup_host(&self.addr).await.is_err();
+                    self.monitor.connect_failed(dns_failure);
+                    if dns_failure && !self.retry_on_dns_failure {
+                        self.monitor.set(ConnectionState::Stopped);
+                        return;
+                    }
+                    dns_failure
+                }
+            };
+            let delay = self.backoff.next_delay();
+            self.monitor.set(ConnectionState::WaitingRetry { delay, dns_failure });
+            time::sleep(delay).await;
+        }
+    }
+
+    async fn push_loop(&mut self, mut tcp_stream: TcpStream) {
+        if tcp_stream.set_nodelay(true).is_err() {
+            return;
+        }
+        // subscribe only after connecting so the collector starts with fresh packets
+        let mut pkt_receiver = self.pkt_sender.subscribe();
+        loop {
+            match pkt_receiver.recv().await {
+                Ok(packet) => {
+                    if tcp_stream.write_all(&packet).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(n)) => {
+                    println!("{} lagged, skipped {} packets", self.addr, n);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+        let _ = tcp_stream.shutdown().await;
+    }
+}
+
+// Run one pusher per target until 'shutdown' completes.
+pub async fn start_pushers(
+    targets: Vec<String>,
+    reconnect: ReconnectConfig,
+    pkt_sender: broadcast::Sender<Vec<u8>>,
+    shutdown: impl Future,
+) {
+    if targets.is_empty() {
+        return;
+    }
+    let mut pushers = JoinSet::new();
+    for addr in targets {
+        let monitor = Arc::new(ConnectionMonitor::new(&format!("push {}", addr)));
+        tokio::spawn(log_connection_events(
+            monitor.name().to_string(),
+            monitor.subscribe(),
+            monitor.metrics.clone(),
+        ));
+        let mut pusher = TcpPusher {
+            addr,
+            pkt_sender: pkt_sender.clone(),
+            backoff: Backoff::new(&reconnect),
+            retry_on_dns_failure: reconnect.retry_on_dns_failure,
+            monitor,
+        };
+        pushers.spawn(async move { pusher.inf_run().await });
+    }
+
+    tokio::select! {
+        _ = async { while pushers.join_next().await.is_some() {} } => {}
+        _ = shutdown => {
+            println!("Stop pushing streams");
+        }
+    }
+    pushers.shutdown().await;
+}
@@ -0,0 +1,129 @@
+use crate::config_file::{Config, SenderPolicy};
+use crate::tcp_client::forward_packets;
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration};
+
+struct ActiveSender {
+    addr: SocketAddr,
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+// Accepts the playback stream from whichever peer connects; only one peer
+// feeds the speaker at a time, a second one is rejected or preempts the first.
+pub struct TcpRecvServer {
+    port: usize,
+    pkt_size: usize,
+    policy: SenderPolicy,
+    listener: TcpListener,
+    resend: Sender<Vec<u8>>,
+    active: Option<ActiveSender>,
+}
+
+impl TcpRecvServer {
+    pub async fn new(
+        port: usize,
+        pkt_size: usize,
+        policy: SenderPolicy,
+        resend: Sender<Vec<u8>>,
+    ) -> crate::Result<TcpRecvServer> {
+        let addr = format!("{}:{}", "0.0.0.0", port);
+        let listener = TcpListener::bind(&addr).await?;
+        Ok(TcpRecvServer {
+            port,
+            pkt_size,
+            policy,
+            listener,
+            resend,
+            active: None,
+        })
+    }
+
+    async fn run(&mut self) {
+        println!("accept playback stream on port: {}", self.port);
+        loop {
+            // errors like EMFILE or ECONNABORTED pass, keep listening
+            let (socket, addr) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    println!("Error! Failed to accept playback stream. {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            if let Err(err) = socket.set_nodelay(true) {
+                println!("Error! Failed to set nodelay on playback stream from {}. {}", addr, err);
+                continue;
+            }
+
+            if let Some(active) = self.active.take() {
+                if active.handle.is_finished() {
+                    // previous sender already gone
+                } else if self.policy == SenderPolicy::Reject {
+                    println!("reject playback stream from {}, {} is sending", addr, active.addr);
+                    self.active = Some(active);
+                    drop(socket);
+                    continue;
+                } else {
+                    println!("{} preempts playback stream from {}", addr, active.addr);
+                    let _ = active.stop.send(());
+                    let _ = active.handle.await;
+                }
+            }
+
+            println!("playback stream from {}", addr);
+            self.active = Some(self.spawn_handler(socket, addr));
+        }
+    }
+
+    fn spawn_handler(&self, mut socket: TcpStream, addr: SocketAddr) -> ActiveSender {
+        let (stop, stopped) = oneshot::channel::<()>();
+        let pkt_size = self.pkt_size;
+        let resend = self.resend.clone();
+        let handle = tokio::spawn(async move {
+            tokio::select! {
+                _ = forward_packets(&mut socket, pkt_size, &resend) => {}
+                _ = stopped => {}
+            }
+            println!("playback stream from {} closed", addr);
+        });
+        ActiveSender { addr, stop, handle }
+    }
+}
+
+pub(crate) async fn start_recv_server(
+    cfg: Arc<Config>,
+    resend: Sender<Vec<u8>>,
+    shutdown: impl Future,
+) {
+    let pkt_size = cfg.tcp_receiver.header_len +
+        cfg.tcp_receiver.n_channel * cfg.tcp_receiver.sample_per_packet * cfg.tcp_receiver.sample_format.bytes();
+    let mut server = match TcpRecvServer::new(
+        cfg.tcp_receiver.port,
+        pkt_size,
+        cfg.tcp_receiver.on_new_sender,
+        resend,
+    ).await {
+        Ok(server) => server,
+        Err(err) => {
+            println!("Error! Failed to listen for playback stream. {}", err);
+            return;
+        }
+    };
+    tokio::select! {
+        _ = server.run() => {}
+        _ = shutdown => {
+            println!("Cleaning up playback stream server");
+        }
+    }
+    if let Some(active) = server.active.take() {
+        let _ = active.stop.send(());
+    }
+}
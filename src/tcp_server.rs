@@ -34,7 +34,7 @@ impl TcpServer {
         let server = TcpServer {
             port,
             listener,
-            limit_connections: Arc::new(Semaphore::new(max_clients.into())),
+            limit_connections: Arc::new(Semaphore::new(max_clients)),
             // packet_buf,
             // notifyee,
             pkt_sender,
@@ -110,14 +110,14 @@ pub struct SocketHandler {
 
 impl SocketHandler {
     async fn run(&mut self) -> crate::Result<()> {
-        while self.shutdown.load(Ordering::Relaxed) != true {
+        while !self.shutdown.load(Ordering::Relaxed) {
             // self.notifyee.notified().await;
             // let packet = self.packet_buf.load();
             let packet = self.pkt_receiver.recv().await?;
             tokio::select! {
                 // res = self.socket.write_all(packet.as_ref()) => {
                 res = self.socket.write_all(&packet) => {
-                    if let Err(_) = res {
+                    if res.is_err() {
                         self.shutdown.store(true, Ordering::Relaxed);
                     }
                 }
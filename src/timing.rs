@@ -0,0 +1,277 @@
+use crate::audio_clock::AudioClock;
+use crate::config_file::{ReferenceClockKind, TimingConfig};
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::sync::Arc;
+use arc_swap::ArcSwap;
+use tokio::time::{self, Duration, Instant};
+
+// A clock that can be read with clock_gettime; a PTP hardware clock is
+// addressed through the open file descriptor of its /dev/ptpN device.
+pub struct ReferenceClock {
+    kind: ReferenceClockKind,
+    clock_id: libc::clockid_t,
+    _device: Option<File>,
+}
+
+impl ReferenceClock {
+    pub fn open(cfg: &TimingConfig) -> crate::Result<ReferenceClock> {
+        match cfg.reference_clock {
+            ReferenceClockKind::Realtime => Ok(ReferenceClock::realtime()),
+            #[cfg(target_os = "linux")]
+            ReferenceClockKind::Tai => Ok(ReferenceClock {
+                kind: ReferenceClockKind::Tai,
+                clock_id: libc::CLOCK_TAI,
+                _device: None,
+            }),
+            #[cfg(target_os = "linux")]
+            ReferenceClockKind::Ptp => {
+                use std::os::unix::io::AsRawFd;
+                let device = File::open(&cfg.ptp_device)?;
+                // FD_TO_CLOCKID from linux/posix-timers.h
+                let clock_id = ((!device.as_raw_fd()) << 3) | 3;
+                let clock = ReferenceClock {
+                    kind: ReferenceClockKind::Ptp,
+                    clock_id,
+                    _device: Some(device),
+                };
+                clock.try_now_ns()?;
+                Ok(clock)
+            }
+            #[cfg(not(target_os = "linux"))]
+            _ => Err("only the realtime clock is supported on this platform".into()),
+        }
+    }
+
+    pub fn realtime() -> ReferenceClock {
+        ReferenceClock {
+            kind: ReferenceClockKind::Realtime,
+            clock_id: libc::CLOCK_REALTIME,
+            _device: None,
+        }
+    }
+
+    pub fn kind(&self) -> ReferenceClockKind {
+        self.kind
+    }
+
+    fn try_now_ns(&self) -> std::io::Result<i64> {
+        clock_ns(self.clock_id)
+    }
+
+    pub fn now_ns(&self) -> i64 {
+        self.try_now_ns().unwrap_or(0)
+    }
+}
+
+fn clock_ns(clock_id: libc::clockid_t) -> std::io::Result<i64> {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    if unsafe { libc::clock_gettime(clock_id, &mut ts) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // time_t and c_long are 32 bits on some of our targets
+    #[allow(clippy::unnecessary_cast)]
+    Ok(ts.tv_sec as i64 * 1_000_000_000 + ts.tv_nsec as i64)
+}
+
+// Offsets from the JACK clock (jack_get_time) to the wall and reference clocks.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClockOffsets {
+    pub wall_ns: i64,
+    pub reference_ns: i64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CaptureTime {
+    // unix time (CLOCK_REALTIME) in nanoseconds
+    pub wall_ns: i64,
+    // time on the reference clock in nanoseconds
+    pub reference_ns: i64,
+}
+
+// Converts JACK clock readings to wall and reference clock time; the offsets
+// are refreshed periodically by 'run_timing'.
+pub struct TimeBase {
+    clock: ReferenceClock,
+    offsets: ArcSwap<ClockOffsets>,
+}
+
+impl TimeBase {
+    // Needs an open JACK client, otherwise the JACK clock is not running.
+    pub fn new(clock: ReferenceClock) -> TimeBase {
+        let offsets = ArcSwap::from_pointee(measure_offsets(&clock));
+        TimeBase { clock, offsets }
+    }
+
+    pub fn offsets(&self) -> ClockOffsets {
+        **self.offsets.load()
+    }
+
+    pub fn capture_time(&self, jack_micros: i64) -> CaptureTime {
+        let offsets = self.offsets();
+        let jack_ns = jack_micros * 1000;
+        CaptureTime {
+            wall_ns: jack_ns + offsets.wall_ns,
+            reference_ns: jack_ns + offsets.reference_ns,
+        }
+    }
+
+    fn resync(&self) {
+        self.offsets.store(Arc::new(measure_offsets(&self.clock)));
+    }
+}
+
+// Read the JACK clock on both sides of the wall and reference clock readings
+// and keep the tightest of a few tries.
+fn measure_offsets(clock: &ReferenceClock) -> ClockOffsets {
+    let mut best = (i64::MAX, ClockOffsets::default());
+    for _ in 0..5 {
+        let before = jack::get_time() as i64 * 1000;
+        let wall = clock_ns(libc::CLOCK_REALTIME).unwrap_or(0);
+        let reference = clock.now_ns();
+        let after = jack::get_time() as i64 * 1000;
+        let mid = before + (after - before) / 2;
+        if after - before < best.0 {
+            best = (
+                after - before,
+                ClockOffsets {
+                    wall_ns: wall - mid,
+                    reference_ns: reference - mid,
+                },
+            );
+        }
+    }
+    best.1
+}
+
+// Least squares fit of wall clock time against the audio sample counter over
+// a sliding window; the slope gives the true sample rate of the audio clock.
+pub struct DriftEstimator {
+    nominal_rate: f64,
+    window: usize,
+    points: VecDeque<(u64, i64)>,
+    first: Option<(u64, i64)>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DriftReport {
+    pub drift_ppm: f64,
+    // wall clock minus the time predicted by counting samples at the
+    // nominal rate since the first point
+    pub offset_ms: f64,
+}
+
+impl DriftEstimator {
+    pub fn new(nominal_rate: usize, window: usize) -> DriftEstimator {
+        DriftEstimator {
+            nominal_rate: nominal_rate as f64,
+            window: window.max(2),
+            points: VecDeque::new(),
+            first: None,
+        }
+    }
+
+    pub fn add(&mut self, sample: u64, wall_ns: i64) {
+        self.first.get_or_insert((sample, wall_ns));
+        if self.points.len() == self.window {
+            self.points.pop_front();
+        }
+        self.points.push_back((sample, wall_ns));
+    }
+
+    pub fn report(&self) -> Option<DriftReport> {
+        if self.points.len() < 2 {
+            return None;
+        }
+        let (s0, t0) = self.points[0];
+        let n = self.points.len() as f64;
+        let xs = self.points.iter().map(|&(s, _)| (s - s0) as f64);
+        let ys = self.points.iter().map(|&(_, t)| (t - t0) as f64);
+        let mean_x = xs.clone().sum::<f64>() / n;
+        let mean_y = ys.clone().sum::<f64>() / n;
+        let (mut sxx, mut sxy) = (0.0, 0.0);
+        for (x, y) in xs.zip(ys) {
+            sxx += (x - mean_x) * (x - mean_x);
+            sxy += (x - mean_x) * (y - mean_y);
+        }
+        if sxx <= 0.0 {
+            return None;
+        }
+        let ns_per_sample = sxy / sxx;
+        let drift_ppm = (1e9 / ns_per_sample / self.nominal_rate - 1.0) * 1e6;
+
+        let (first_sample, first_ns) = self.first?;
+        let &(last_sample, last_ns) = self.points.back()?;
+        let counted_ns = (last_sample - first_sample) as f64 * 1e9 / self.nominal_rate;
+        let offset_ms = ((last_ns - first_ns) as f64 - counted_ns) / 1e6;
+        Some(DriftReport { drift_ppm, offset_ms })
+    }
+}
+
+// Keep the clock offsets fresh and print how the audio clock drifts against
+// the wall clock and how far the reference clock is from the wall clock.
+pub async fn run_timing(
+    cfg: TimingConfig,
+    time_base: Arc<TimeBase>,
+    audio_clock: Arc<AudioClock>,
+    sample_rate: usize,
+) {
+    let sync_interval = Duration::from_millis(cfg.sync_interval_ms.max(10));
+    let window = (cfg.drift_window_s * 1000 / sync_interval.as_millis() as u64) as usize;
+    let mut estimator = DriftEstimator::new(sample_rate, window);
+    let mut interval = time::interval(sync_interval);
+    let mut last_report = Instant::now();
+    loop {
+        interval.tick().await;
+        time_base.resync();
+        let reference = audio_clock.load();
+        if reference.jack_micros == 0 {
+            continue;
+        }
+        let capture = time_base.capture_time(reference.jack_micros);
+        estimator.add(reference.sample, capture.wall_ns);
+
+        if cfg.report_interval_s > 0
+            && last_report.elapsed() >= Duration::from_secs(cfg.report_interval_s)
+        {
+            last_report = Instant::now();
+            let offsets = time_base.offsets();
+            if let Some(report) = estimator.report() {
+                println!(
+                    "timing: audio clock drift {:+.2} ppm, offset {:+.3} ms to wall clock; {:?} - wall = {:+.3} ms",
+                    report.drift_ppm,
+                    report.offset_ms,
+                    time_base.clock.kind(),
+                    (offsets.reference_ns - offsets.wall_ns) as f64 / 1e6,
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn needs_two_points() {
+        let mut estimator = DriftEstimator::new(16000, 10);
+        assert!(estimator.report().is_none());
+        estimator.add(0, 0);
+        assert!(estimator.report().is_none());
+    }
+
+    #[test]
+    fn measures_fast_audio_clock() {
+        // 100 ppm fast: 160016 samples every 10 s of wall clock
+        let mut estimator = DriftEstimator::new(16000, 5);
+        for i in 0..20_u64 {
+            estimator.add(1000 + i * 160_016, 5_000_000_000 + i as i64 * 10_000_000_000);
+        }
+        let report = estimator.report().unwrap();
+        assert!((report.drift_ppm - 100.0).abs() < 1e-3, "{report:?}");
+        // 19 intervals counted 1 ms longer than the wall clock, window or not
+        assert!((report.offset_ms + 19.0).abs() < 1e-6, "{report:?}");
+    }
+}
@@ -0,0 +1,235 @@
+use crate::config_file::VadConfig;
+use crate::fft::{Complex, Fft};
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use tokio::sync::broadcast;
+
+// how fast the noise floor may rise, in dB per second
+const FLOOR_RISE_DB: f32 = 3.0;
+// part of the energy that must lie in the speech band
+const MIN_BAND_RATIO: f32 = 0.6;
+// spectral flatness of white noise is about 0.56, voiced speech is well below
+const MAX_FLATNESS: f32 = 0.4;
+const SPEECH_BAND_HZ: (f32, f32) = (100.0, 4000.0);
+
+// Decides per packet whether one channel carries speech: loud enough above a
+// tracked noise floor, with most energy in the speech band and a spectrum
+// less flat than noise. The floor follows quieter packets at once and rises
+// slowly, but not during speech. The decision holds for the hang time after
+// the last packet that looked like speech.
+pub struct VoiceDetector {
+    // pipeline output channel the detector listens to
+    channel: usize,
+    threshold_db: f32,
+    min_level_db: f32,
+    floor_rise_db: f32,
+    hang_packets: usize,
+    fft: Fft,
+    window: Vec<f32>,
+    band: (usize, usize),
+    spectrum: Vec<Complex>,
+    noise_floor_db: Option<f32>,
+    hang: usize,
+}
+
+impl VoiceDetector {
+    pub fn new(cfg: &VadConfig, channel: usize, sample_per_packet: usize, sample_rate: usize) -> VoiceDetector {
+        let n_fft = sample_per_packet.next_power_of_two();
+        let bin_hz = sample_rate as f32 / n_fft as f32;
+        VoiceDetector {
+            channel,
+            threshold_db: cfg.threshold_db,
+            min_level_db: cfg.min_level_db,
+            floor_rise_db: FLOOR_RISE_DB * sample_per_packet as f32 / sample_rate as f32,
+            hang_packets: (cfg.hang_ms * sample_rate / 1000).div_ceil(sample_per_packet),
+            fft: Fft::new(n_fft),
+            window: (0..sample_per_packet)
+                .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / sample_per_packet as f32).cos())
+                .collect(),
+            band: (
+                ((SPEECH_BAND_HZ.0 / bin_hz) as usize).max(1),
+                ((SPEECH_BAND_HZ.1 / bin_hz) as usize).min(n_fft / 2),
+            ),
+            spectrum: vec![Complex::ZERO; n_fft],
+            noise_floor_db: None,
+            hang: 0,
+        }
+    }
+
+    pub fn channel(&self) -> usize {
+        self.channel
+    }
+
+    // One packet of samples of the channel; true while in speech.
+    pub fn detect(&mut self, samples: &[f32]) -> bool {
+        let power = samples.iter().map(|x| x * x).sum::<f32>() / samples.len().max(1) as f32;
+        let level_db = 10.0 * (power + 1e-20).log10();
+        let floor_db = *self.noise_floor_db.get_or_insert(level_db);
+        let speech = self.looks_like_speech(samples, level_db, level_db - floor_db);
+        if level_db < floor_db {
+            self.noise_floor_db = Some(level_db);
+        } else if !speech {
+            self.noise_floor_db = Some(floor_db + self.floor_rise_db.min(level_db - floor_db));
+        }
+
+        if speech {
+            self.hang = self.hang_packets + 1;
+        }
+        if self.hang > 0 {
+            self.hang -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn looks_like_speech(&mut self, samples: &[f32], level_db: f32, above_floor: f32) -> bool {
+        if level_db < self.min_level_db || above_floor < self.threshold_db {
+            return false;
+        }
+        for (x, (s, w)) in self.spectrum.iter_mut().zip(samples.iter().zip(self.window.iter())) {
+            *x = Complex::new(s * w, 0.0);
+        }
+        for x in self.spectrum[samples.len().min(self.window.len())..].iter_mut() {
+            *x = Complex::ZERO;
+        }
+        self.fft.forward(&mut self.spectrum);
+
+        let half = self.spectrum.len() / 2;
+        let total: f32 = self.spectrum[1..=half].iter().map(|x| x.norm_sqr() + 1e-20).sum();
+        let band = &self.spectrum[self.band.0..=self.band.1];
+        let band_total: f32 = band.iter().map(|x| x.norm_sqr() + 1e-20).sum();
+        let log_mean = band.iter().map(|x| (x.norm_sqr() + 1e-20).ln()).sum::<f32>() / band.len() as f32;
+        let flatness = log_mean.exp() / (band_total / band.len() as f32);
+        band_total / total >= MIN_BAND_RATIO && flatness <= MAX_FLATNESS
+    }
+}
+
+// Passes on the packets of speech segments only, each segment preceded by
+// the packets of the pre-roll so the start of speech is not cut off.
+pub struct SpeechGate {
+    pre_roll: VecDeque<Vec<u8>>,
+    pre_roll_packets: usize,
+    sender: broadcast::Sender<Vec<u8>>,
+}
+
+impl SpeechGate {
+    pub fn new(pre_roll_packets: usize, sender: broadcast::Sender<Vec<u8>>) -> SpeechGate {
+        SpeechGate {
+            pre_roll: VecDeque::with_capacity(pre_roll_packets),
+            pre_roll_packets,
+            sender,
+        }
+    }
+
+    pub fn push(&mut self, packet: &[u8], speech: bool) {
+        if !speech {
+            if self.pre_roll_packets > 0 {
+                if self.pre_roll.len() == self.pre_roll_packets {
+                    self.pre_roll.pop_front();
+                }
+                self.pre_roll.push_back(packet.to_vec());
+            }
+            return;
+        }
+        // no clients is not an error
+        for packet in self.pre_roll.drain(..) {
+            let _ = self.sender.send(packet);
+        }
+        let _ = self.sender.send(packet.to_vec());
+    }
+
+    // Passes a device down packet on at once, so listeners learn about the
+    // outage; what was held back from before it is no use as pre-roll.
+    pub fn push_device_down(&mut self, packet: &[u8]) {
+        self.pre_roll.clear();
+        let _ = self.sender.send(packet.to_vec());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: usize = 16000;
+    const PACKET: usize = 160;
+
+    struct Noise(u32);
+
+    impl Noise {
+        fn packet(&mut self, amplitude: f32) -> Vec<f32> {
+            (0..PACKET)
+                .map(|_| {
+                    self.0 ^= self.0 << 13;
+                    self.0 ^= self.0 >> 17;
+                    self.0 ^= self.0 << 5;
+                    (self.0 as f32 / u32::MAX as f32 * 2.0 - 1.0) * amplitude
+                })
+                .collect()
+        }
+    }
+
+    // harmonics of a 200 Hz voice, falling off with frequency like speech
+    fn voiced(packet: usize) -> Vec<f32> {
+        (0..PACKET)
+            .map(|i| {
+                let t = (packet * PACKET + i) as f32 / SAMPLE_RATE as f32;
+                (1..19).map(|k| (2.0 * PI * 200.0 * k as f32 * t).sin() * 0.05 / k as f32).sum()
+            })
+            .collect()
+    }
+
+    fn detector() -> (VoiceDetector, usize) {
+        let cfg = VadConfig::default();
+        let hang_packets = cfg.hang_ms * SAMPLE_RATE / 1000 / PACKET;
+        (VoiceDetector::new(&cfg, 0, PACKET, SAMPLE_RATE), hang_packets)
+    }
+
+    #[test]
+    fn detects_voice_and_holds_it_for_hang_time() {
+        let (mut detector, hang_packets) = detector();
+        let mut noise = Noise(1);
+        for _ in 0..50 {
+            assert!(!detector.detect(&noise.packet(0.003)));
+        }
+        for packet in 0..20 {
+            assert!(detector.detect(&voiced(packet)), "packet {packet}");
+        }
+        for _ in 0..hang_packets {
+            assert!(detector.detect(&noise.packet(0.003)));
+        }
+        assert!(!detector.detect(&noise.packet(0.003)));
+    }
+
+    #[test]
+    fn loud_noise_is_not_speech() {
+        let (mut detector, _) = detector();
+        let mut noise = Noise(7);
+        for _ in 0..50 {
+            detector.detect(&noise.packet(0.003));
+        }
+        for _ in 0..20 {
+            assert!(!detector.detect(&noise.packet(0.3)));
+        }
+    }
+
+    #[test]
+    fn gate_sends_pre_roll_ahead_of_speech() {
+        let (sender, mut receiver) = broadcast::channel(16);
+        let mut gate = SpeechGate::new(2, sender);
+        for id in 0..3 {
+            gate.push(&[id], false);
+        }
+        assert!(receiver.try_recv().is_err());
+        gate.push(&[3], true);
+        gate.push(&[4], false);
+        let sent: Vec<Vec<u8>> = std::iter::from_fn(|| receiver.try_recv().ok()).collect();
+        assert_eq!(sent, vec![vec![1], vec![2], vec![3]]);
+
+        // a device down packet goes out at once and ends the pre-roll
+        gate.push_device_down(&[5]);
+        gate.push(&[6], true);
+        let sent: Vec<Vec<u8>> = std::iter::from_fn(|| receiver.try_recv().ok()).collect();
+        assert_eq!(sent, vec![vec![5], vec![6]]);
+    }
+}